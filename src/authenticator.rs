@@ -1,15 +1,18 @@
 use crate::authenticator_delegate::{AuthenticatorDelegate, DefaultAuthenticatorDelegate, Retry};
+use crate::encryption::KeyProvider;
 use crate::refresh::RefreshFlow;
-use crate::storage::{hash_scopes, DiskTokenStorage, MemoryStorage, TokenStorage};
-use crate::types::{ApplicationSecret, GetToken, RefreshResult, RequestError, Token};
+use crate::storage::{
+    hash_scopes, AsyncTokenStorage, DiskTokenStorage, MemoryStorage, TokenFileMetadata,
+};
+use crate::types::{ApplicationSecret, GetToken, RequestError, Token};
 
 use futures::{future, prelude::*};
 use tokio_timer;
 
-use std::error::Error;
 use std::io;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 /// Authenticator abstracts different `GetToken` implementations behind one type and handles
 /// caching received tokens. It's important to use it (instead of the flows directly) because
@@ -23,7 +26,7 @@ use std::sync::{Arc, Mutex};
 /// Due to token requests being rare, this should not result in a too bad performance problem.
 struct AuthenticatorImpl<
     T: GetToken,
-    S: TokenStorage,
+    S: AsyncTokenStorage,
     AD: AuthenticatorDelegate,
     C: hyper::client::connect::Connect,
 > {
@@ -31,6 +34,7 @@ struct AuthenticatorImpl<
     inner: Arc<Mutex<T>>,
     store: Arc<Mutex<S>>,
     delegate: AD,
+    refresh_token_expiry_warning: Duration,
 }
 
 /// A trait implemented for any hyper::Client as well as teh DefaultHyperClient.
@@ -68,6 +72,12 @@ pub trait AuthFlow<C> {
     type TokenGetter: GetToken;
 
     fn build_token_getter(self, client: hyper::Client<C>) -> Self::TokenGetter;
+
+    /// The application secret the flow obtains tokens with, if any. It is recorded in token
+    /// files as their metadata.
+    fn application_secret(&self) -> Option<&ApplicationSecret> {
+        None
+    }
 }
 
 /// An authenticator can be used with `InstalledFlow`'s or `DeviceFlow`'s and
@@ -75,7 +85,7 @@ pub trait AuthFlow<C> {
 /// disk.
 pub struct Authenticator<
     T: AuthFlow<C::Connector>,
-    S: TokenStorage,
+    S: AsyncTokenStorage,
     AD: AuthenticatorDelegate,
     C: HyperClientBuilder,
 > {
@@ -83,6 +93,7 @@ pub struct Authenticator<
     token_getter: T,
     store: io::Result<S>,
     delegate: AD,
+    refresh_token_expiry_warning: Duration,
 }
 
 impl<T> Authenticator<T, MemoryStorage, DefaultAuthenticatorDelegate, DefaultHyperClient>
@@ -109,6 +120,7 @@ where
             token_getter: flow,
             store: Ok(MemoryStorage::new()),
             delegate: DefaultAuthenticatorDelegate,
+            refresh_token_expiry_warning: DEFAULT_REFRESH_TOKEN_EXPIRY_WARNING,
         }
     }
 }
@@ -116,7 +128,7 @@ where
 impl<T, S, AD, C> Authenticator<T, S, AD, C>
 where
     T: AuthFlow<C::Connector>,
-    S: TokenStorage,
+    S: AsyncTokenStorage,
     AD: AuthenticatorDelegate,
     C: HyperClientBuilder,
 {
@@ -134,6 +146,23 @@ where
             token_getter: self.token_getter,
             store: self.store,
             delegate: self.delegate,
+            refresh_token_expiry_warning: self.refresh_token_expiry_warning,
+        }
+    }
+
+    /// Keep tokens in the provided storage. Any `TokenStorage` may be used, as well as an
+    /// `AsyncTokenStorage` for storages that are not local, e.g. a database shared by several
+    /// processes.
+    pub fn persist_tokens_to<NewS: AsyncTokenStorage>(
+        self,
+        storage: NewS,
+    ) -> Authenticator<T, NewS, AD, C> {
+        Authenticator {
+            client: self.client,
+            token_getter: self.token_getter,
+            store: Ok(storage),
+            delegate: self.delegate,
+            refresh_token_expiry_warning: self.refresh_token_expiry_warning,
         }
     }
 
@@ -142,12 +171,41 @@ where
         self,
         path: P,
     ) -> Authenticator<T, DiskTokenStorage, AD, C> {
-        let disk_storage = DiskTokenStorage::new(path.as_ref().to_str().unwrap());
+        let metadata = token_file_metadata(self.token_getter.application_secret());
+        let disk_storage = DiskTokenStorage::new(path.as_ref().to_str().unwrap())
+            .map(|storage| storage.with_metadata(metadata))
+            .map_err(io::Error::from);
         Authenticator {
             client: self.client,
             token_getter: self.token_getter,
             store: disk_storage,
             delegate: self.delegate,
+            refresh_token_expiry_warning: self.refresh_token_expiry_warning,
+        }
+    }
+
+    /// Persist tokens to disk in the provided filename, encrypted with a key obtained from
+    /// `key_provider`. Failures to decrypt the file are reported to the authenticator delegate's
+    /// `token_storage_failure()`.
+    pub fn persist_tokens_to_encrypted_disk<P, K>(
+        self,
+        path: P,
+        key_provider: K,
+    ) -> Authenticator<T, DiskTokenStorage, AD, C>
+    where
+        P: AsRef<Path>,
+        K: 'static + KeyProvider + Send + Sync,
+    {
+        let metadata = token_file_metadata(self.token_getter.application_secret());
+        let disk_storage =
+            DiskTokenStorage::new_encrypted(path.as_ref().to_str().unwrap(), key_provider)
+                .with_metadata(metadata);
+        Authenticator {
+            client: self.client,
+            token_getter: self.token_getter,
+            store: Ok(disk_storage),
+            delegate: self.delegate,
+            refresh_token_expiry_warning: self.refresh_token_expiry_warning,
         }
     }
 
@@ -161,6 +219,16 @@ where
             token_getter: self.token_getter,
             store: self.store,
             delegate: delegate,
+            refresh_token_expiry_warning: self.refresh_token_expiry_warning,
+        }
+    }
+
+    /// Warn the authenticator delegate with `refresh_token_expiring()` when a token is refreshed
+    /// whose refresh token expires within `window`. The default is 24 hours.
+    pub fn refresh_token_expiry_warning(self, window: Duration) -> Self {
+        Authenticator {
+            refresh_token_expiry_warning: window,
+            ..self
         }
     }
 
@@ -183,13 +251,85 @@ where
             inner,
             store,
             delegate: self.delegate,
+            refresh_token_expiry_warning: self.refresh_token_expiry_warning,
         })
     }
 }
 
+/// The metadata of token files written for a flow using `secret`.
+fn token_file_metadata(secret: Option<&ApplicationSecret>) -> TokenFileMetadata {
+    TokenFileMetadata {
+        client_id: secret.map(|s| s.client_id.clone()),
+        token_uri: secret.map(|s| s.token_uri.clone()),
+        ..Default::default()
+    }
+}
+
+/// How long before the expiry of a refresh token the delegate is warned about it, unless
+/// configured otherwise.
+const DEFAULT_REFRESH_TOKEN_EXPIRY_WARNING: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The future driving one iteration of the token lookup loop in `AuthenticatorImpl::token()`.
+type LoopFuture = Box<dyn Future<Item = future::Loop<Token, ()>, Error = RequestError> + Send>;
+
+/// Check that `token` grants all `scopes`, or that `delegate` accepts it anyway.
+fn check_granted_scopes<AD: AuthenticatorDelegate>(
+    delegate: &mut AD,
+    scopes: &[String],
+    token: &Token,
+) -> Result<(), RequestError> {
+    let missing = token.missing_scopes(scopes);
+    if !missing.is_empty() {
+        let granted = token.granted_scopes().unwrap_or_default();
+        if !delegate.scopes_not_granted(&missing, &granted) {
+            return Err(RequestError::InvalidScope(format!(
+                "Scopes not granted: {}",
+                missing.join(" ")
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Save `token` in `store` and end the lookup loop with it, consulting the delegate if the
+/// storage fails, or if the token doesn't grant all `scopes`.
+fn store_token<S, AD>(
+    store: &Arc<Mutex<S>>,
+    mut delegate: AD,
+    scope_key: u64,
+    scopes: Vec<String>,
+    token: Token,
+) -> LoopFuture
+where
+    S: 'static + AsyncTokenStorage + Send,
+    AD: 'static + AuthenticatorDelegate + Send,
+{
+    if let Err(e) = check_granted_scopes(&mut delegate, &scopes, &token) {
+        return Box::new(Err(e).into_future());
+    }
+    let stored = store
+        .lock()
+        .unwrap()
+        .store(scope_key, scopes, Some(token.clone()));
+    Box::new(stored.then(move |r| -> LoopFuture {
+        match r {
+            Ok(()) => Box::new(Ok(future::Loop::Break(token)).into_future()),
+            Err(e) => match delegate.token_storage_failure(true, &e) {
+                Retry::Skip => Box::new(Ok(future::Loop::Break(token)).into_future()),
+                Retry::Abort => {
+                    Box::new(Err(RequestError::Cache(S::error_kind(&e), Box::new(e))).into_future())
+                }
+                Retry::After(d) => {
+                    Box::new(tokio_timer::sleep(d).then(|_| Ok(future::Loop::Continue(()))))
+                }
+            },
+        }
+    }))
+}
+
 impl<
         GT: 'static + GetToken + Send,
-        S: 'static + TokenStorage + Send,
+        S: 'static + AsyncTokenStorage + Send,
         AD: 'static + AuthenticatorDelegate + Send,
         C: 'static + hyper::client::connect::Connect + Clone + Send,
     > GetToken for AuthenticatorImpl<GT, S, AD, C>
@@ -213,121 +353,456 @@ impl<
     {
         let (scope_key, scopes) = hash_scopes(scopes);
         let store = self.store.clone();
-        let mut delegate = self.delegate.clone();
+        let delegate = self.delegate.clone();
         let client = self.client.clone();
         let appsecret = self.inner.lock().unwrap().application_secret();
         let gettoken = self.inner.clone();
-        let loopfn = move |()| -> Box<
-            dyn Future<Item = future::Loop<Token, ()>, Error = RequestError> + Send,
-        > {
-            // How well does this work with tokio?
-            match store.lock().unwrap().get(
-                scope_key.clone(),
-                &scopes.iter().map(|s| s.as_str()).collect(),
-            ) {
-                Ok(Some(t)) => {
-                    if !t.expired() {
-                        return Box::new(Ok(future::Loop::Break(t)).into_future());
-                    }
-                    // Implement refresh flow.
-                    let refresh_token = t.refresh_token.clone();
-                    let mut delegate = delegate.clone();
-                    let store = store.clone();
-                    let scopes = scopes.clone();
-                    let refresh_fut = RefreshFlow::refresh_token(
-                        client.clone(),
-                        appsecret.clone(),
-                        refresh_token,
-                    )
-                        .and_then(move |rr| -> Box<dyn Future<Item=future::Loop<Token, ()>, Error=RequestError> + Send> {
-                            match rr {
-                                RefreshResult::Error(ref e) => {
-                                    delegate.token_refresh_failed(
-                                        format!("{}", e.description().to_string()),
-                                        &Some("the request has likely timed out".to_string()),
-                                        );
-                                    Box::new(Err(RequestError::Refresh(rr)).into_future())
-                                }
-                                RefreshResult::RefreshError(ref s, ref ss) => {
-                                    delegate.token_refresh_failed(
-                                        format!("{} {}", s, ss.clone().map(|s| format!("({})", s)).unwrap_or("".to_string())),
-                                        &Some("the refresh token is likely invalid and your authorization has been revoked".to_string()),
-                                        );
-                                    Box::new(Err(RequestError::Refresh(rr)).into_future())
-                                }
-                                RefreshResult::Success(t) => {
-                                    if let Err(e) = store.lock().unwrap().set(scope_key, &scopes.iter().map(|s| s.as_str()).collect(), Some(t.clone())) {
-                                        match delegate.token_storage_failure(true, &e) {
-                                            Retry::Skip => Box::new(Ok(future::Loop::Break(t)).into_future()),
-                                            Retry::Abort => Box::new(Err(RequestError::Cache(Box::new(e))).into_future()),
-                                            Retry::After(d) => Box::new(
-                                                tokio_timer::sleep(d)
-                                                .then(|_| Ok(future::Loop::Continue(()))),
+        let expiry_warning = self.refresh_token_expiry_warning;
+        let loopfn = move |()| -> LoopFuture {
+            // The store is only locked while the lookup is started; remote storages complete
+            // it asynchronously.
+            let lookup = store.lock().unwrap().load(scope_key, scopes.clone());
+            let store = store.clone();
+            let mut delegate = delegate.clone();
+            let client = client.clone();
+            let appsecret = appsecret.clone();
+            let gettoken = gettoken.clone();
+            let scopes = scopes.clone();
+            Box::new(lookup.then(move |r| -> LoopFuture {
+                match r {
+                    // A token whose refresh token has expired is only of use as long as the
+                    // access token is valid; otherwise a new one is obtained below.
+                    Ok(Some(t)) if !t.expired() || !t.refresh_token_expired() => {
+                        if !t.expired() {
+                            // The token may be stored for scopes it doesn't grant, if the
+                            // delegate accepted that; it has to do so for every use.
+                            let checked = check_granted_scopes(&mut delegate, &scopes, &t);
+                            return Box::new(checked.map(|()| future::Loop::Break(t)).into_future());
+                        }
+                        // Implement refresh flow.
+                        let refresh_token = t.refresh_token.clone();
+                        let previous_scope = t.scope;
+                        let previous_refresh_expiry = t.refresh_token_expires_in_timestamp;
+                        let refresh_fut =
+                            RefreshFlow::refresh_token(client, appsecret, refresh_token.clone())
+                                .then(move |rr| -> LoopFuture {
+                                    match rr {
+                                        Err(e) => {
+                                            let hint = match e {
+                                                RequestError::ClientError(_) => {
+                                                    Some("the request has likely timed out")
+                                                }
+                                                RequestError::Refresh(_) => Some("the refresh token is likely invalid and your authorization has been revoked"),
+                                                _ => None,
+                                            };
+                                            delegate.token_refresh_failed(
+                                                e.to_string(),
+                                                &hint.map(str::to_string),
+                                            );
+                                            Box::new(Err(e).into_future())
+                                        }
+                                        Ok(mut t) => {
+                                            // An omitted scope means the scope is unchanged.
+                                            if t.scope.is_none() {
+                                                t.scope = previous_scope;
+                                            }
+                                            // The expiry of a rotated refresh token is unknown.
+                                            if t.refresh_token_expires_in_timestamp.is_none()
+                                                && t.refresh_token == refresh_token
+                                            {
+                                                t.refresh_token_expires_in_timestamp =
+                                                    previous_refresh_expiry;
+                                            }
+                                            if let Some(expiry) = t.refresh_token_expiry_date() {
+                                                let warn_after = chrono::Duration::from_std(
+                                                    expiry_warning,
                                                 )
-                                                as Box<
-                                                dyn Future<
-                                                Item = future::Loop<Token, ()>,
-                                                Error = RequestError> + Send>,
+                                                .ok()
+                                                .and_then(|w| expiry.checked_sub_signed(w));
+                                                let warn = match warn_after {
+                                                    Some(warn_after) => {
+                                                        warn_after <= chrono::Utc::now()
+                                                    }
+                                                    // Too long ago to be represented.
+                                                    None => true,
+                                                };
+                                                if warn {
+                                                    delegate.refresh_token_expiring(&expiry);
+                                                }
+                                            }
+                                            store_token(&store, delegate, scope_key, scopes, t)
                                         }
-                                    } else {
-                                        Box::new(Ok(future::Loop::Break(t)).into_future())
                                     }
-                                },
-                            }
-                        });
-                    Box::new(refresh_fut)
-                }
-                Ok(None) => {
-                    let store = store.clone();
-                    let scopes = scopes.clone();
-                    let mut delegate = delegate.clone();
-                    Box::new(
+                                });
+                        Box::new(refresh_fut)
+                    }
+                    Ok(_) => Box::new(
                         gettoken
                             .lock()
                             .unwrap()
                             .token(scopes.clone())
-                            .and_then(move |t| {
-                                if let Err(e) = store.lock().unwrap().set(
-                                    scope_key,
-                                    &scopes.iter().map(|s| s.as_str()).collect(),
-                                    Some(t.clone()),
-                                ) {
-                                    match delegate.token_storage_failure(true, &e) {
-                                        Retry::Skip => {
-                                            Box::new(Ok(future::Loop::Break(t)).into_future())
-                                        }
-                                        Retry::Abort => Box::new(
-                                            Err(RequestError::Cache(Box::new(e))).into_future(),
-                                        ),
-                                        Retry::After(d) => Box::new(
-                                            tokio_timer::sleep(d)
-                                                .then(|_| Ok(future::Loop::Continue(()))),
-                                        )
-                                            as Box<
-                                                dyn Future<
-                                                        Item = future::Loop<Token, ()>,
-                                                        Error = RequestError,
-                                                    > + Send,
-                                            >,
-                                    }
-                                } else {
-                                    Box::new(Ok(future::Loop::Break(t)).into_future())
-                                }
-                            }),
-                    )
-                }
-                Err(err) => match delegate.token_storage_failure(false, &err) {
-                    Retry::Abort | Retry::Skip => {
-                        return Box::new(Err(RequestError::Cache(Box::new(err))).into_future())
-                    }
-                    Retry::After(d) => {
-                        return Box::new(
+                            .and_then(move |t| store_token(&store, delegate, scope_key, scopes, t)),
+                    ),
+                    Err(err) => match delegate.token_storage_failure(false, &err) {
+                        Retry::Abort | Retry::Skip => {
+                            let kind = S::error_kind(&err);
+                            Box::new(Err(RequestError::Cache(kind, Box::new(err))).into_future())
+                        }
+                        Retry::After(d) => Box::new(
                             tokio_timer::sleep(d).then(|_| Ok(future::Loop::Continue(()))),
-                        )
-                    }
-                },
-            }
+                        ),
+                    },
+                }
+            }))
         };
         Box::new(future::loop_fn((), loopfn))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StorageFuture;
+
+    use std::collections::HashMap;
+    use std::error::Error;
+    use std::fmt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A token source handing out a fresh token on every call, granting the given scopes.
+    struct CountingFlow(Arc<AtomicUsize>, Option<&'static str>, ApplicationSecret);
+
+    impl CountingFlow {
+        fn new(issued: Arc<AtomicUsize>, granted: Option<&'static str>) -> CountingFlow {
+            let secret = ApplicationSecret {
+                client_id: "counting-client".to_string(),
+                token_uri: format!("{}/authenticator/token", mockito::server_url()),
+                ..Default::default()
+            };
+            CountingFlow(issued, granted, secret)
+        }
+    }
+
+    impl<C> AuthFlow<C> for CountingFlow {
+        type TokenGetter = CountingGetter;
+
+        fn build_token_getter(self, _: hyper::Client<C>) -> CountingGetter {
+            CountingGetter(self.0, self.1, self.2)
+        }
+
+        fn application_secret(&self) -> Option<&ApplicationSecret> {
+            Some(&self.2)
+        }
+    }
+
+    struct CountingGetter(Arc<AtomicUsize>, Option<&'static str>, ApplicationSecret);
+
+    impl GetToken for CountingGetter {
+        fn token<I, T>(
+            &mut self,
+            _: I,
+        ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+        where
+            T: Into<String>,
+            I: IntoIterator<Item = T>,
+        {
+            let n = self.0.fetch_add(1, Ordering::SeqCst);
+            let mut token = Token {
+                access_token: format!("accesstoken-{}", n),
+                refresh_token: "refreshtoken".to_string(),
+                token_type: "Bearer".to_string(),
+                expires_in: Some(3600),
+                id_token: None,
+                scope: self.1.map(str::to_string),
+                extra: HashMap::new(),
+                refresh_token_expires_in: None,
+                refresh_token_expires_in_timestamp: None,
+                expires_in_timestamp: None,
+            };
+            token.set_expiry_absolute();
+            Box::new(Ok(token).into_future())
+        }
+
+        fn api_key(&mut self) -> Option<String> {
+            None
+        }
+
+        fn application_secret(&self) -> ApplicationSecret {
+            self.2.clone()
+        }
+    }
+
+    #[derive(Debug)]
+    struct RemoteError;
+
+    impl fmt::Display for RemoteError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            "remote store unavailable".fmt(f)
+        }
+    }
+
+    impl Error for RemoteError {}
+
+    /// Stands in for a remote token store: a map shared by all handles, answering after a delay.
+    #[derive(Clone, Default)]
+    struct FakeRemoteStorage {
+        tokens: Arc<Mutex<HashMap<u64, Token>>>,
+        available: Arc<Mutex<bool>>,
+    }
+
+    impl FakeRemoteStorage {
+        fn delayed<T: 'static + Send>(&self, value: T) -> StorageFuture<T, RemoteError> {
+            let available = *self.available.lock().unwrap();
+            Box::new(
+                tokio_timer::sleep(Duration::from_millis(10))
+                    .map_err(|_| RemoteError)
+                    .and_then(move |_| {
+                        if available {
+                            Ok(value)
+                        } else {
+                            Err(RemoteError)
+                        }
+                    }),
+            )
+        }
+    }
+
+    impl AsyncTokenStorage for FakeRemoteStorage {
+        type Error = RemoteError;
+
+        fn store(
+            &mut self,
+            scope_hash: u64,
+            _: Vec<String>,
+            token: Option<Token>,
+        ) -> StorageFuture<(), RemoteError> {
+            let tokens = self.tokens.clone();
+            Box::new(self.delayed(()).map(move |()| {
+                let mut tokens = tokens.lock().unwrap();
+                match token {
+                    Some(t) => tokens.insert(scope_hash, t),
+                    None => tokens.remove(&scope_hash),
+                };
+            }))
+        }
+
+        fn load(
+            &mut self,
+            scope_hash: u64,
+            _: Vec<String>,
+        ) -> StorageFuture<Option<Token>, RemoteError> {
+            let tokens = self.tokens.clone();
+            Box::new(
+                self.delayed(())
+                    .map(move |()| tokens.lock().unwrap().get(&scope_hash).cloned()),
+            )
+        }
+    }
+
+    #[test]
+    fn test_async_storage_shared_by_workers() {
+        let remote = FakeRemoteStorage::default();
+        *remote.available.lock().unwrap() = true;
+        let issued = Arc::new(AtomicUsize::new(0));
+        let mut worker1 = Authenticator::new(CountingFlow::new(issued.clone(), None))
+            .persist_tokens_to(remote.clone())
+            .build()
+            .unwrap();
+        let mut worker2 = Authenticator::new(CountingFlow::new(issued.clone(), None))
+            .persist_tokens_to(remote.clone())
+            .build()
+            .unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let scopes = vec!["https://www.googleapis.com/auth/pubsub"];
+
+        let first = rt.block_on(worker1.token(scopes.clone())).unwrap();
+        assert_eq!("accesstoken-0", first.access_token);
+        // The second worker finds the token issued to the first one.
+        let second = rt.block_on(worker2.token(scopes.clone())).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(1, issued.load(Ordering::SeqCst));
+        assert_eq!(1, remote.tokens.lock().unwrap().len());
+
+        // Failures of the remote store are reported as cache errors.
+        *remote.available.lock().unwrap() = false;
+        match rt.block_on(worker2.token(scopes)) {
+            Err(RequestError::Cache(kind, e)) => {
+                assert_eq!(crate::types::ErrorKind::Storage, kind);
+                assert_eq!("remote store unavailable", e.to_string());
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[derive(Clone)]
+    struct StrictDelegate(Arc<Mutex<Vec<String>>>);
+
+    impl AuthenticatorDelegate for StrictDelegate {
+        fn scopes_not_granted(&mut self, missing: &[String], _: &[&str]) -> bool {
+            self.0.lock().unwrap().extend(missing.iter().cloned());
+            false
+        }
+    }
+
+    #[test]
+    fn test_scopes_not_granted() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let issued = Arc::new(AtomicUsize::new(0));
+        let scopes = vec!["read", "write"];
+
+        // By default, narrower tokens are accepted. They are only found for granted scopes.
+        let mut auth = Authenticator::new(CountingFlow::new(issued.clone(), Some("read")))
+            .build()
+            .unwrap();
+        let tok = rt.block_on(auth.token(scopes.clone())).unwrap();
+        assert_eq!(Some(vec!["read"]), tok.granted_scopes());
+        assert_eq!(tok, rt.block_on(auth.token(vec!["read"])).unwrap());
+        assert_ne!(tok, rt.block_on(auth.token(vec!["write"])).unwrap());
+
+        let missing = Arc::new(Mutex::new(vec![]));
+        let mut auth = Authenticator::new(CountingFlow::new(issued.clone(), Some("read")))
+            .delegate(StrictDelegate(missing.clone()))
+            .build()
+            .unwrap();
+        match rt.block_on(auth.token(scopes)) {
+            Err(RequestError::InvalidScope(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(vec!["write".to_string()], *missing.lock().unwrap());
+
+        // A stored token is checked, too, before it is handed out.
+        let remote = FakeRemoteStorage::default();
+        *remote.available.lock().unwrap() = true;
+        let mut accepting = Authenticator::new(CountingFlow::new(issued.clone(), Some("read")))
+            .persist_tokens_to(remote.clone())
+            .build()
+            .unwrap();
+        rt.block_on(accepting.token(vec!["read", "write"])).unwrap();
+        let missing = Arc::new(Mutex::new(vec![]));
+        let mut strict = Authenticator::new(CountingFlow::new(issued.clone(), Some("read")))
+            .persist_tokens_to(remote)
+            .delegate(StrictDelegate(missing.clone()))
+            .build()
+            .unwrap();
+        let before = issued.load(Ordering::SeqCst);
+        match rt.block_on(strict.token(vec!["write", "read"])) {
+            Err(RequestError::InvalidScope(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(before, issued.load(Ordering::SeqCst));
+        assert_eq!(vec!["write".to_string()], *missing.lock().unwrap());
+    }
+
+    #[derive(Clone)]
+    struct ExpiryDelegate(Arc<Mutex<Vec<chrono::DateTime<chrono::Utc>>>>);
+
+    impl AuthenticatorDelegate for ExpiryDelegate {
+        fn refresh_token_expiring(&mut self, expires_at: &chrono::DateTime<chrono::Utc>) {
+            self.0.lock().unwrap().push(*expires_at);
+        }
+    }
+
+    #[test]
+    fn test_disk_token_file_metadata() {
+        let mut location = std::env::temp_dir();
+        location.push(format!(
+            "yup-oauth2-auth-metadata-{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&location);
+        let flow = CountingFlow::new(Arc::new(AtomicUsize::new(0)), None);
+        let token_uri = flow.2.token_uri.clone();
+        let mut auth = Authenticator::new(flow)
+            .persist_tokens_to_disk(&location)
+            .build()
+            .unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(auth.token(vec!["read"])).unwrap();
+
+        let metadata = DiskTokenStorage::new(location.to_str().unwrap())
+            .unwrap()
+            .metadata()
+            .unwrap();
+        assert_eq!(Some("counting-client"), metadata.client_id.as_deref());
+        assert_eq!(Some(token_uri), metadata.token_uri);
+        std::fs::remove_file(&location).unwrap();
+        let _ = std::fs::remove_file(format!("{}.lock", location.to_str().unwrap()));
+    }
+
+    #[test]
+    fn test_refresh_token_expiry() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let remote = FakeRemoteStorage::default();
+        *remote.available.lock().unwrap() = true;
+        let issued = Arc::new(AtomicUsize::new(0));
+        let warnings = Arc::new(Mutex::new(vec![]));
+        let mut auth = Authenticator::new(CountingFlow::new(issued.clone(), None))
+            .persist_tokens_to(remote.clone())
+            .delegate(ExpiryDelegate(warnings.clone()))
+            .build()
+            .unwrap();
+        let scopes = vec!["read"];
+        let (scope_key, _) = hash_scopes(scopes.clone());
+        let now = chrono::Utc::now().timestamp();
+        let stale = |refresh_expiry: i64| Token {
+            access_token: "stale".to_string(),
+            refresh_token: "refreshtoken".to_string(),
+            token_type: "Bearer".to_string(),
+            expires_in: None,
+            refresh_token_expires_in: None,
+            refresh_token_expires_in_timestamp: Some(refresh_expiry),
+            id_token: None,
+            scope: None,
+            extra: HashMap::new(),
+            expires_in_timestamp: Some(now - 10),
+        };
+
+        // An expired refresh token is not used; a new token is obtained right away.
+        remote
+            .tokens
+            .lock()
+            .unwrap()
+            .insert(scope_key, stale(now - 10));
+        let tok = rt.block_on(auth.token(scopes.clone())).unwrap();
+        assert_eq!("accesstoken-0", tok.access_token);
+        assert!(warnings.lock().unwrap().is_empty());
+
+        // A refresh token expiring soon is used, but the delegate is warned.
+        let _m = mockito::mock("POST", "/authenticator/token")
+            .with_body(
+                r#"{"access_token": "refreshed", "token_type": "Bearer", "expires_in": 3600}"#,
+            )
+            .create();
+        remote
+            .tokens
+            .lock()
+            .unwrap()
+            .insert(scope_key, stale(now + 3600));
+        let tok = rt.block_on(auth.token(scopes.clone())).unwrap();
+        assert_eq!("refreshed", tok.access_token);
+        assert_eq!(Some(now + 3600), tok.refresh_token_expires_in_timestamp);
+        assert_eq!(
+            vec![tok.refresh_token_expiry_date().unwrap()],
+            *warnings.lock().unwrap()
+        );
+        assert_eq!(1, issued.load(Ordering::SeqCst));
+
+        // The warning window is configurable; windows of any length are accepted.
+        for (window, warned) in &[(60, false), (u64::MAX, true)] {
+            warnings.lock().unwrap().clear();
+            let mut auth = Authenticator::new(CountingFlow::new(issued.clone(), None))
+                .persist_tokens_to(remote.clone())
+                .delegate(ExpiryDelegate(warnings.clone()))
+                .refresh_token_expiry_warning(Duration::from_secs(*window))
+                .build()
+                .unwrap();
+            remote
+                .tokens
+                .lock()
+                .unwrap()
+                .insert(scope_key, stale(now + 3600));
+            rt.block_on(auth.token(scopes.clone())).unwrap();
+            assert_eq!(*warned, !warnings.lock().unwrap().is_empty());
+        }
+    }
+}
@@ -91,6 +91,26 @@ pub trait AuthenticatorDelegate: Clone {
     /// The server denied the attempt to obtain a request code
     fn request_failure(&mut self, _: RequestError) {}
 
+    /// Called if a token doesn't grant all requested scopes, e.g. because the user
+    /// deselected some on the consent screen. `missing` are the requested scopes that were not
+    /// granted. This is asked again whenever such a token is taken from the token storage.
+    ///
+    /// Return true to use the token anyway, or false to fail with `RequestError::InvalidScope`.
+    /// Note that some providers report scopes differently than requested, e.g. Google grants
+    /// `https://www.googleapis.com/auth/userinfo.email` for `email`.
+    fn scopes_not_granted(&mut self, missing: &[String], granted: &[&str]) -> bool {
+        let _ = (missing, granted);
+        true
+    }
+
+    /// Called when an access token was refreshed, but the refresh token will expire within a
+    /// day, or the window set with `Authenticator::refresh_token_expiry_warning()`. Once it has expired, a new token must be obtained interactively, so this is an
+    /// opportunity to ask the user to authorize the application again at a convenient time.
+    /// This call is made for the delegate's information only.
+    fn refresh_token_expiring(&mut self, expires_at: &DateTime<Utc>) {
+        let _ = expires_at;
+    }
+
     /// Called if we could not acquire a refresh token for a reason possibly specified
     /// by the server.
     /// This call is made for the delegate's information only.
@@ -89,6 +89,10 @@ where
             wait: Duration::from_secs(1200),
         }
     }
+
+    fn application_secret(&self) -> Option<&ApplicationSecret> {
+        Some(&self.application_secret)
+    }
 }
 
 /// The DeviceFlow implementation.
@@ -472,6 +476,39 @@ mod tests {
 
             _m.assert();
         }
+        // The token response includes an ID token.
+        {
+            let code_response = r#"{"device_code": "devicecode", "user_code": "usercode", "verification_url": "https://example.com/verify", "expires_in": 1234567, "interval": 1}"#;
+            let _m = mockito::mock("POST", "/code")
+                .with_status(200)
+                .with_body(code_response)
+                .create();
+            let claims = r#"{"iss": "https://accounts.google.com", "sub": "1234", "aud": "client", "exp": 1500, "iat": 900, "email": "user@example.com"}"#;
+            let id_token = format!(
+                "e30.{}.c2ln",
+                base64::encode_config(claims, base64::URL_SAFE_NO_PAD)
+            );
+            let token_response = format!(
+                r#"{{"access_token": "accesstoken", "refresh_token": "refreshtoken", "token_type": "Bearer", "expires_in": 1234567, "id_token": "{}"}}"#,
+                id_token
+            );
+            let _m = mockito::mock("POST", "/token")
+                .match_body(mockito::Matcher::Regex(
+                    ".*client_secret=iuMPN6Ne1PD7cos29Tk9rlqH&code=devicecode.*".to_string(),
+                ))
+                .with_status(200)
+                .with_body(token_response)
+                .create();
+
+            let tok = rt
+                .block_on(flow.token(vec!["openid", "email"]))
+                .expect("block_on");
+            let claims = tok.id_token_claims().unwrap().unwrap();
+            assert_eq!("1234", claims.sub);
+            assert_eq!(Some("user@example.com"), claims.email.as_deref());
+
+            _m.assert();
+        }
         // Code is not delivered.
         {
             let code_response =
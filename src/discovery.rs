@@ -0,0 +1,331 @@
+//! This module discovers the endpoints of an OAuth 2.0 provider from its issuer URL, using
+//! [OpenID Connect Discovery](https://openid.net/specs/openid-connect-discovery-1_0.html) or,
+//! for providers that don't support it, authorization server metadata
+//! ([RFC 8414](https://tools.ietf.org/html/rfc8414)).
+//!
+//! The discovered `ProviderMetadata` configures the flows of this crate, so that only a client
+//! ID (and secret) needs to be known:
+//!
+//! ```no_run
+//! use futures::prelude::*;
+//! use yup_oauth2::{Discovery, InstalledFlow, InstalledFlowReturnMethod};
+//!
+//! let flow = Discovery::new("https://accounts.google.com")
+//!     .fetch()
+//!     .map(|metadata| {
+//!         metadata
+//!             .application_secret("my-client-id", "my-client-secret")
+//!             .map(|secret| {
+//!                 InstalledFlow::new(secret, InstalledFlowReturnMethod::HTTPRedirectEphemeral)
+//!             })
+//!     });
+//! ```
+
+use crate::authenticator::{DefaultHyperClient, HyperClientBuilder};
+use crate::authenticator_delegate::DefaultFlowDelegate;
+use crate::device::DeviceFlow;
+use crate::types::{ApplicationSecret, RequestError};
+use crate::verifier::IdTokenVerifier;
+
+use futures::stream::Stream;
+use futures::{future, prelude::*};
+
+const OPENID_CONFIGURATION: &str = "/.well-known/openid-configuration";
+const AUTHORIZATION_SERVER_METADATA: &str = "/.well-known/oauth-authorization-server";
+
+/// The metadata of an OAuth 2.0 provider, as far as it is relevant to this crate.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: Option<String>,
+    pub token_endpoint: Option<String>,
+    pub device_authorization_endpoint: Option<String>,
+    pub revocation_endpoint: Option<String>,
+    pub introspection_endpoint: Option<String>,
+    pub userinfo_endpoint: Option<String>,
+    pub jwks_uri: Option<String>,
+    #[serde(default)]
+    pub scopes_supported: Vec<String>,
+    #[serde(default)]
+    pub grant_types_supported: Vec<String>,
+    /// PKCE methods, e.g. `S256`.
+    #[serde(default)]
+    pub code_challenge_methods_supported: Vec<String>,
+    /// Client authentication methods at the token endpoint, e.g. `client_secret_basic`.
+    #[serde(default)]
+    pub token_endpoint_auth_methods_supported: Vec<String>,
+}
+
+impl ProviderMetadata {
+    /// An `ApplicationSecret` for the installed flow, using the discovered authorization and
+    /// token endpoints, if the provider has both.
+    pub fn application_secret<I: Into<String>, S: Into<String>>(
+        &self,
+        client_id: I,
+        client_secret: S,
+    ) -> Option<ApplicationSecret> {
+        let auth_uri = self.authorization_endpoint.clone()?;
+        Some(ApplicationSecret {
+            auth_uri,
+            ..self.token_secret(client_id, client_secret)?
+        })
+    }
+
+    /// A `DeviceFlow` using the discovered device authorization endpoint, if the provider has
+    /// one.
+    pub fn device_flow<I: Into<String>, S: Into<String>>(
+        &self,
+        client_id: I,
+        client_secret: S,
+    ) -> Option<DeviceFlow<DefaultFlowDelegate>> {
+        let device_code_url = self.device_authorization_endpoint.clone()?;
+        Some(
+            DeviceFlow::new(self.token_secret(client_id, client_secret)?)
+                .device_code_url(device_code_url),
+        )
+    }
+
+    /// An `IdTokenVerifier` for ID tokens of this issuer for `audience`, if the provider
+    /// publishes its keys.
+    pub fn id_token_verifier<A: Into<String>>(
+        &self,
+        audience: A,
+    ) -> Option<IdTokenVerifier<DefaultHyperClient>> {
+        let jwks_uri = self.jwks_uri.clone()?;
+        Some(IdTokenVerifier::new(
+            jwks_uri,
+            self.issuer.clone(),
+            audience,
+        ))
+    }
+
+    /// An `ApplicationSecret` with just the token endpoint, if the provider has one.
+    fn token_secret<I: Into<String>, S: Into<String>>(
+        &self,
+        client_id: I,
+        client_secret: S,
+    ) -> Option<ApplicationSecret> {
+        Some(ApplicationSecret {
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            token_uri: self.token_endpoint.clone()?,
+            ..Default::default()
+        })
+    }
+}
+
+/// Fetches the `ProviderMetadata` of an issuer.
+pub struct Discovery<C> {
+    client: C,
+    issuer: String,
+}
+
+impl Discovery<DefaultHyperClient> {
+    /// Discover the provider identified by the `issuer` URL.
+    pub fn new<I: Into<String>>(issuer: I) -> Self {
+        Discovery {
+            client: DefaultHyperClient,
+            issuer: issuer.into(),
+        }
+    }
+}
+
+impl<C> Discovery<C>
+where
+    C: HyperClientBuilder,
+    C::Connector: 'static,
+{
+    /// Use the provided hyper client.
+    pub fn hyper_client<NewC: HyperClientBuilder>(self, hyper_client: NewC) -> Discovery<NewC> {
+        Discovery {
+            client: hyper_client,
+            issuer: self.issuer,
+        }
+    }
+
+    /// Fetch the OpenID Connect configuration of the issuer, or its authorization server
+    /// metadata if it doesn't have one.
+    pub fn fetch(self) -> Box<dyn Future<Item = ProviderMetadata, Error = RequestError> + Send> {
+        let client = self.client.build_hyper_client();
+        let issuer = self.issuer.trim_end_matches('/').to_string();
+        let (oidc_url, rfc8414_url) = metadata_urls(&issuer);
+        Box::new(
+            fetch_metadata(&client, oidc_url)
+                .and_then(move |metadata| match metadata {
+                    Some(metadata) => future::Either::A(future::ok(Some(metadata))),
+                    None => future::Either::B(fetch_metadata(&client, rfc8414_url)),
+                })
+                .and_then(move |metadata| {
+                    let metadata = metadata.ok_or_else(|| {
+                        RequestError::BadServerResponse(format!(
+                            "No provider metadata found for {}",
+                            issuer
+                        ))
+                    })?;
+                    // The issuer must match, or a provider could impersonate another one.
+                    if metadata.issuer.trim_end_matches('/') != issuer {
+                        return Err(RequestError::BadServerResponse(format!(
+                            "Provider metadata for {} is for issuer {}",
+                            issuer, metadata.issuer
+                        )));
+                    }
+                    Ok(metadata)
+                }),
+        )
+    }
+}
+
+/// Returns the OpenID Connect and RFC 8414 metadata URLs of `issuer`. The latter inserts the
+/// well-known path between host and path of the issuer.
+fn metadata_urls(issuer: &str) -> (String, String) {
+    let oidc_url = format!("{}{}", issuer, OPENID_CONFIGURATION);
+    let path_start = issuer
+        .find("://")
+        .and_then(|i| issuer[i + 3..].find('/').map(|j| i + 3 + j))
+        .unwrap_or(issuer.len());
+    let rfc8414_url = format!(
+        "{}{}{}",
+        &issuer[..path_start],
+        AUTHORIZATION_SERVER_METADATA,
+        &issuer[path_start..]
+    );
+    (oidc_url, rfc8414_url)
+}
+
+/// Fetch metadata from `url`. Returns None if there is none.
+fn fetch_metadata<C: 'static + hyper::client::connect::Connect>(
+    client: &hyper::Client<C, hyper::Body>,
+    url: String,
+) -> impl Future<Item = Option<ProviderMetadata>, Error = RequestError> {
+    let request = hyper::Request::get(url)
+        .body(hyper::Body::empty())
+        .map_err(|e| RequestError::UserError(e.to_string()));
+    let client = client.clone();
+    future::result(request)
+        .and_then(move |request| {
+            client
+                .request(request)
+                .and_then(|response| {
+                    let status = response.status();
+                    response
+                        .into_body()
+                        .concat2()
+                        .map(move |body| (status, body))
+                })
+                .map_err(RequestError::ClientError)
+        })
+        .and_then(|(status, body)| {
+            if status == hyper::StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            if !status.is_success() {
+                return Err(RequestError::BadServerResponse(format!(
+                    "Provider metadata request failed with {}",
+                    status
+                )));
+            }
+            serde_json::from_slice(&body)
+                .map(Some)
+                .map_err(RequestError::JSONError)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mockito::{self, mock};
+
+    #[test]
+    fn test_metadata_urls() {
+        assert_eq!(
+            (
+                "https://example.com/.well-known/openid-configuration".to_string(),
+                "https://example.com/.well-known/oauth-authorization-server".to_string()
+            ),
+            metadata_urls("https://example.com")
+        );
+        assert_eq!(
+            (
+                "https://example.com/tenant/.well-known/openid-configuration".to_string(),
+                "https://example.com/.well-known/oauth-authorization-server/tenant".to_string()
+            ),
+            metadata_urls("https://example.com/tenant")
+        );
+    }
+
+    #[test]
+    fn test_discovery() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let server_url = mockito::server_url();
+        let issuer = format!("{}/oidc", server_url);
+        let _m = mock("GET", "/oidc/.well-known/openid-configuration")
+            .with_body(
+                serde_json::json!({
+                    "issuer": issuer,
+                    "authorization_endpoint": format!("{}/auth", server_url),
+                    "token_endpoint": format!("{}/token", server_url),
+                    "device_authorization_endpoint": format!("{}/device", server_url),
+                    "jwks_uri": format!("{}/certs", server_url),
+                    "code_challenge_methods_supported": ["plain", "S256"],
+                    "response_types_supported": ["code"],
+                })
+                .to_string(),
+            )
+            .create();
+        let metadata = rt.block_on(Discovery::new(issuer.clone()).fetch()).unwrap();
+        assert_eq!(
+            Some(format!("{}/token", server_url)),
+            metadata.token_endpoint
+        );
+        assert_eq!(
+            vec!["plain", "S256"],
+            metadata.code_challenge_methods_supported
+        );
+        assert!(metadata.token_endpoint_auth_methods_supported.is_empty());
+        let secret = metadata.application_secret("client", "secret").unwrap();
+        assert_eq!(format!("{}/auth", server_url), secret.auth_uri);
+        assert_eq!(format!("{}/token", server_url), secret.token_uri);
+        assert!(metadata.device_flow("client", "secret").is_some());
+        assert!(metadata.id_token_verifier("client").is_some());
+
+        // Fall back to RFC 8414 metadata.
+        let _m404 = mock("GET", "/oauth/.well-known/openid-configuration")
+            .with_status(404)
+            .create();
+        let _m = mock("GET", "/.well-known/oauth-authorization-server/oauth")
+            .with_body(
+                serde_json::json!({
+                    "issuer": format!("{}/oauth", server_url),
+                    "token_endpoint": format!("{}/token", server_url),
+                    "introspection_endpoint": format!("{}/introspect", server_url),
+                })
+                .to_string(),
+            )
+            .create();
+        let metadata = rt
+            .block_on(Discovery::new(format!("{}/oauth/", server_url)).fetch())
+            .unwrap();
+        assert_eq!(
+            Some(format!("{}/introspect", server_url)),
+            metadata.introspection_endpoint
+        );
+        assert!(metadata.application_secret("client", "secret").is_none());
+        assert!(metadata.device_flow("client", "secret").is_none());
+
+        // Metadata of another issuer is rejected.
+        let _m = mock("GET", "/evil/.well-known/openid-configuration")
+            .with_body(serde_json::json!({ "issuer": issuer }).to_string())
+            .create();
+        match rt.block_on(Discovery::new(format!("{}/evil", server_url)).fetch()) {
+            Err(RequestError::BadServerResponse(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // An issuer that isn't a valid URL is an error, not a panic.
+        match rt.block_on(Discovery::new("https://bad host").fetch()) {
+            Err(RequestError::UserError(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+}
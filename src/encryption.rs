@@ -0,0 +1,307 @@
+//! Encryption at rest for token files written by `DiskTokenStorage`.
+//!
+//! The serialized tokens are sealed with ChaCha20-Poly1305, an authenticated cipher: a file that
+//! was tampered with, or that is opened with the wrong key, is rejected instead of being parsed.
+//! The 256 bit key is supplied by a `KeyProvider`; implementations for raw keys, key files,
+//! environment variables and passphrases are provided.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// Length of the keys returned by a `KeyProvider`, in bytes.
+pub const KEY_LEN: usize = 32;
+
+const SALT_LEN: usize = 16;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const ALGORITHM: &str = "chacha20-poly1305";
+/// Binds the ciphertext to its purpose; a sealed token file can't be passed off as anything else.
+const AAD: &[u8] = b"yup-oauth2 token storage";
+
+/// Supplies the key used to encrypt and decrypt token files.
+pub trait KeyProvider {
+    /// Return the key. `salt` is stored in the clear next to the encrypted data; providers
+    /// that derive a key from a low-entropy secret (such as `PassphraseKey`) should use it,
+    /// others can ignore it.
+    fn key(&self, salt: &[u8]) -> Result<[u8; KEY_LEN], io::Error>;
+}
+
+/// A key that is known to the application.
+pub struct RawKey(pub [u8; KEY_LEN]);
+
+impl KeyProvider for RawKey {
+    fn key(&self, _: &[u8]) -> Result<[u8; KEY_LEN], io::Error> {
+        Ok(self.0)
+    }
+}
+
+/// A key stored in a file, either as 32 raw bytes or base64-encoded.
+pub struct KeyFile(pub PathBuf);
+
+impl KeyProvider for KeyFile {
+    fn key(&self, _: &[u8]) -> Result<[u8; KEY_LEN], io::Error> {
+        let contents = fs::read(&self.0)?;
+        if contents.len() == KEY_LEN {
+            return key_from_slice(&contents);
+        }
+        let text = String::from_utf8_lossy(&contents);
+        decode_key(text.trim())
+    }
+}
+
+/// A base64-encoded key stored in an environment variable.
+pub struct EnvKey(pub String);
+
+impl KeyProvider for EnvKey {
+    fn key(&self, _: &[u8]) -> Result<[u8; KEY_LEN], io::Error> {
+        let value = env::var(&self.0).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Token encryption key variable {}: {}", self.0, e),
+            )
+        })?;
+        decode_key(value.trim())
+    }
+}
+
+/// A key derived from a passphrase using PBKDF2-HMAC-SHA256.
+pub struct PassphraseKey {
+    passphrase: String,
+    iterations: NonZeroU32,
+    /// The most recently derived key and its salt, as derivation is deliberately slow.
+    derived: Mutex<Option<(Vec<u8>, [u8; KEY_LEN])>>,
+}
+
+impl PassphraseKey {
+    /// Create a new PassphraseKey. The default number of PBKDF2 iterations is 100,000.
+    pub fn new<S: Into<String>>(passphrase: S) -> PassphraseKey {
+        PassphraseKey {
+            passphrase: passphrase.into(),
+            iterations: NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+            derived: Mutex::new(None),
+        }
+    }
+
+    /// Use the provided number of PBKDF2 iterations.
+    pub fn iterations(self, iterations: NonZeroU32) -> Self {
+        PassphraseKey {
+            iterations,
+            derived: Mutex::new(None),
+            ..self
+        }
+    }
+}
+
+impl KeyProvider for PassphraseKey {
+    fn key(&self, salt: &[u8]) -> Result<[u8; KEY_LEN], io::Error> {
+        let mut derived = self.derived.lock().unwrap();
+        if let Some((ref cached_salt, key)) = *derived {
+            if &cached_salt[..] == salt {
+                return Ok(key);
+            }
+        }
+        let mut key = [0; KEY_LEN];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            self.iterations,
+            salt,
+            self.passphrase.as_bytes(),
+            &mut key,
+        );
+        *derived = Some((salt.to_vec(), key));
+        Ok(key)
+    }
+}
+
+fn key_from_slice(bytes: &[u8]) -> Result<[u8; KEY_LEN], io::Error> {
+    if bytes.len() != KEY_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Token encryption key must be {} bytes long, not {}",
+                KEY_LEN,
+                bytes.len()
+            ),
+        ));
+    }
+    let mut key = [0; KEY_LEN];
+    key.copy_from_slice(bytes);
+    Ok(key)
+}
+
+fn decode_key(encoded: &str) -> Result<[u8; KEY_LEN], io::Error> {
+    let bytes = base64::decode(encoded).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Token encryption key is not valid base64: {}", e),
+        )
+    })?;
+    key_from_slice(&bytes)
+}
+
+/// On-disk representation of an encrypted token file.
+#[derive(Serialize, Deserialize)]
+struct SealedFile {
+    encrypted: Sealed,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    alg: String,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Seals and opens token files with a key from a `KeyProvider`.
+pub(crate) struct Sealer {
+    provider: Box<dyn KeyProvider + Send + Sync>,
+    /// Salt of the file last opened; reused when writing so that derived keys can be cached.
+    salt: Mutex<Option<Vec<u8>>>,
+    rng: SystemRandom,
+}
+
+impl Sealer {
+    pub(crate) fn new<K: 'static + KeyProvider + Send + Sync>(provider: K) -> Sealer {
+        Sealer {
+            provider: Box::new(provider),
+            salt: Mutex::new(None),
+            rng: SystemRandom::new(),
+        }
+    }
+
+    fn aead_key(&self, salt: &[u8]) -> Result<LessSafeKey, io::Error> {
+        let key = self.provider.key(salt)?;
+        let unbound = UnboundKey::new(&CHACHA20_POLY1305, &key)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid encryption key"))?;
+        Ok(LessSafeKey::new(unbound))
+    }
+
+    fn random(&self, buf: &mut [u8]) -> Result<(), io::Error> {
+        self.rng.fill(buf).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Couldn't generate random bytes",
+            )
+        })
+    }
+
+    /// Encrypt `plaintext` and return the serialized file contents.
+    pub(crate) fn seal(&self, plaintext: &[u8]) -> Result<String, io::Error> {
+        let salt = {
+            let mut salt = self.salt.lock().unwrap();
+            if salt.is_none() {
+                let mut fresh = vec![0; SALT_LEN];
+                self.random(&mut fresh)?;
+                *salt = Some(fresh);
+            }
+            salt.clone().unwrap()
+        };
+        let mut nonce = [0; NONCE_LEN];
+        self.random(&mut nonce)?;
+
+        let mut in_out = plaintext.to_vec();
+        self.aead_key(&salt)?
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(AAD),
+                &mut in_out,
+            )
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Couldn't encrypt tokens"))?;
+
+        let sealed = SealedFile {
+            encrypted: Sealed {
+                alg: ALGORITHM.to_string(),
+                salt: base64::encode(&salt),
+                nonce: base64::encode(&nonce),
+                ciphertext: base64::encode(&in_out),
+            },
+        };
+        serde_json::to_string(&sealed).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Decrypt the serialized file `contents`.
+    pub(crate) fn open(&self, contents: &str) -> Result<Vec<u8>, io::Error> {
+        let invalid = |msg: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Couldn't decrypt token file: {}", msg),
+            )
+        };
+        let sealed = match serde_json::from_str::<SealedFile>(contents) {
+            Ok(f) => f.encrypted,
+            Err(_) => return Err(invalid("file is not encrypted")),
+        };
+        if sealed.alg != ALGORITHM {
+            return Err(invalid(&format!("unsupported algorithm {}", sealed.alg)));
+        }
+        let salt = base64::decode(&sealed.salt).map_err(|_| invalid("malformed salt"))?;
+        let nonce = base64::decode(&sealed.nonce).map_err(|_| invalid("malformed nonce"))?;
+        let nonce = aead::Nonce::try_assume_unique_for_key(&nonce)
+            .map_err(|_| invalid("malformed nonce"))?;
+        let mut in_out =
+            base64::decode(&sealed.ciphertext).map_err(|_| invalid("malformed ciphertext"))?;
+
+        let plaintext_len = self
+            .aead_key(&salt)?
+            .open_in_place(nonce, Aad::from(AAD), &mut in_out)
+            .map_err(|_| invalid("wrong key, or the file was modified"))?
+            .len();
+        in_out.truncate(plaintext_len);
+        *self.salt.lock().unwrap() = Some(salt);
+        Ok(in_out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_open_roundtrip() {
+        let sealer = Sealer::new(RawKey([7; KEY_LEN]));
+        let sealed = sealer.seal(b"{\"tokens\":[]}").unwrap();
+        assert!(!sealed.contains("tokens"));
+        assert_eq!(sealer.open(&sealed).unwrap(), b"{\"tokens\":[]}".to_vec());
+
+        // Wrong key.
+        let other = Sealer::new(RawKey([8; KEY_LEN]));
+        let err = other.open(&sealed).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("wrong key"));
+        // Plaintext files are not accepted.
+        assert!(sealer.open("{\"tokens\":[]}").is_err());
+    }
+
+    #[test]
+    fn test_passphrase_key() {
+        let key = PassphraseKey::new("correct horse").iterations(NonZeroU32::new(10).unwrap());
+        let k1 = key.key(b"salt1").unwrap();
+        assert_eq!(k1, key.key(b"salt1").unwrap());
+        assert_ne!(k1, key.key(b"salt2").unwrap());
+
+        let sealer = Sealer::new(key);
+        let sealed = sealer.seal(b"secret").unwrap();
+        let reader = Sealer::new(
+            PassphraseKey::new("correct horse").iterations(NonZeroU32::new(10).unwrap()),
+        );
+        assert_eq!(reader.open(&sealed).unwrap(), b"secret".to_vec());
+    }
+
+    #[test]
+    fn test_env_key() {
+        let var = "YUP_OAUTH2_TEST_TOKEN_KEY";
+        env::set_var(var, base64::encode(&[3; KEY_LEN]));
+        assert_eq!(EnvKey(var.to_string()).key(&[]).unwrap(), [3; KEY_LEN]);
+        env::set_var(var, base64::encode(&[3; 5]));
+        assert!(EnvKey(var.to_string()).key(&[]).is_err());
+        env::remove_var(var);
+    }
+}
@@ -0,0 +1,760 @@
+//! This module provides a token source (`GetToken`) for Workload Identity Federation, which lets
+//! workloads running outside of Google Cloud (e.g. on AWS, Azure or GitHub Actions) access Google
+//! APIs without a service account key.
+//!
+//! The workload's own credential (the subject token) is read from a file, fetched from a URL or
+//! printed by an executable, as described by the `credential_source` of an `external_account`
+//! configuration file. It is exchanged for a Google access token at the Security Token Service,
+//! which is then optionally used to impersonate a service account.
+//!
+//! AWS credential sources, whose subject tokens are signed AWS requests, are not supported.
+//!
+//! Resources:
+//! - [Workload Identity Federation](https://cloud.google.com/iam/docs/workload-identity-federation)
+//! - [Executable-sourced
+//!   credentials](https://google.aip.dev/auth/4117#determining-the-subject-token-in-executable-sourced-credentials)
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::authenticator::{DefaultHyperClient, HyperClientBuilder};
+use crate::impersonation::{self, GenerateAccessTokenRequest};
+use crate::storage::{hash_scopes, MemoryStorage, TokenStorage};
+use crate::types::{
+    extra_parameters, ApplicationSecret, GetToken, JsonError, OAuthError, RequestError, Token,
+};
+
+use futures::stream::Stream;
+use futures::sync::oneshot;
+use futures::{future, prelude::*};
+use hyper::header;
+use url::form_urlencoded;
+
+const TOKEN_EXCHANGE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+/// The scope of the STS token if it is used to impersonate a service account.
+const CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+/// Executables are only run if this environment variable is set to 1.
+const ALLOW_EXECUTABLES_VAR: &str = "GOOGLE_EXTERNAL_ACCOUNT_ALLOW_EXECUTABLES";
+const DEFAULT_EXECUTABLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// JSON schema of an `external_account` configuration file, as created by
+/// `gcloud iam workload-identity-pools create-cred-config`.
+///
+/// You can use `helpers::external_account_key_from_file()` to read one.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExternalAccountKey {
+    #[serde(rename = "type")]
+    pub key_type: Option<String>,
+    /// The STS audience, identifying the workload identity pool provider.
+    pub audience: String,
+    pub subject_token_type: String,
+    pub token_url: String,
+    pub credential_source: CredentialSource,
+    pub service_account_impersonation_url: Option<String>,
+    pub service_account_impersonation: Option<ImpersonationOptions>,
+    pub quota_project_id: Option<String>,
+    pub workforce_pool_user_project: Option<String>,
+}
+
+/// Where the subject token comes from. Exactly one of `file`, `url` and `executable` is set.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CredentialSource {
+    pub file: Option<String>,
+    pub url: Option<String>,
+    /// Headers sent with the request to `url`.
+    pub headers: Option<HashMap<String, String>>,
+    pub executable: Option<ExecutableSource>,
+    /// How the subject token is stored in the file or the response from `url`. By default, the
+    /// whole contents are the token.
+    pub format: Option<SubjectTokenFormat>,
+    pub environment_id: Option<String>,
+}
+
+/// The format of a file- or URL-sourced subject token.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SubjectTokenFormat {
+    /// `text` or `json`.
+    #[serde(rename = "type")]
+    pub format_type: String,
+    /// For `json`, the field that holds the token.
+    pub subject_token_field_name: Option<String>,
+}
+
+/// An executable that prints the subject token.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExecutableSource {
+    /// The command line, split at spaces.
+    pub command: String,
+    pub timeout_millis: Option<u64>,
+    /// Where the executable caches its output, if anywhere.
+    pub output_file: Option<String>,
+}
+
+/// Options for impersonating a service account.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImpersonationOptions {
+    pub token_lifetime_seconds: Option<u64>,
+}
+
+/// Builds a token source (`GetToken`) for an `external_account` configuration. Tokens are cached
+/// and requested again shortly before they expire.
+pub struct ExternalAccountAccess<C> {
+    client: C,
+    key: ExternalAccountKey,
+}
+
+impl ExternalAccountAccess<DefaultHyperClient> {
+    /// Create a new ExternalAccountAccess with the provided configuration.
+    pub fn new(key: ExternalAccountKey) -> Self {
+        ExternalAccountAccess {
+            client: DefaultHyperClient,
+            key,
+        }
+    }
+}
+
+impl<C> ExternalAccountAccess<C>
+where
+    C: HyperClientBuilder,
+    C::Connector: 'static,
+{
+    /// Use the provided hyper client.
+    pub fn hyper_client<NewC: HyperClientBuilder>(
+        self,
+        hyper_client: NewC,
+    ) -> ExternalAccountAccess<NewC> {
+        ExternalAccountAccess {
+            client: hyper_client,
+            key: self.key,
+        }
+    }
+
+    /// Build the configured token source.
+    pub fn build(self) -> impl GetToken {
+        ExternalAccountAccessImpl {
+            client: self.client.build_hyper_client(),
+            key: Arc::new(self.key),
+            cache: Arc::new(Mutex::new(MemoryStorage::new())),
+        }
+    }
+}
+
+struct ExternalAccountAccessImpl<C> {
+    client: hyper::Client<C, hyper::Body>,
+    key: Arc<ExternalAccountKey>,
+    cache: Arc<Mutex<MemoryStorage>>,
+}
+
+/// Response of the Security Token Service.
+#[derive(Deserialize, Debug)]
+struct StsResponse {
+    access_token: String,
+    token_type: String,
+    expires_in: Option<i64>,
+    #[serde(flatten)]
+    extra: HashMap<String, serde_json::Value>,
+}
+
+/// Output of a credential executable.
+#[derive(Deserialize, Debug)]
+struct ExecutableResponse {
+    version: u32,
+    success: bool,
+    token_type: Option<String>,
+    id_token: Option<String>,
+    saml_response: Option<String>,
+    expiration_time: Option<i64>,
+    code: Option<String>,
+    message: Option<String>,
+}
+
+fn user_error<S: Into<String>>(msg: S) -> RequestError {
+    RequestError::UserError(msg.into())
+}
+
+/// Extract the subject token from `contents` as described by `format`.
+fn parse_subject_token(
+    contents: &str,
+    format: Option<&SubjectTokenFormat>,
+) -> Result<String, RequestError> {
+    let format = match format {
+        Some(format) if format.format_type == "json" => format,
+        Some(format) if format.format_type != "text" => {
+            return Err(user_error(format!(
+                "Unknown subject token format {}",
+                format.format_type
+            )))
+        }
+        _ => return Ok(contents.trim().to_string()),
+    };
+    let field = format.subject_token_field_name.as_ref().ok_or_else(|| {
+        user_error("subject_token_field_name is required for json subject tokens")
+    })?;
+    let value: serde_json::Value =
+        serde_json::from_str(contents).map_err(RequestError::JSONError)?;
+    match value.get(field).and_then(|v| v.as_str()) {
+        Some(token) => Ok(token.to_string()),
+        None => Err(RequestError::BadServerResponse(format!(
+            "Subject token has no field {}",
+            field
+        ))),
+    }
+}
+
+/// Run the credential executable (or use the output it cached) and return the subject token.
+fn run_executable(
+    key: &ExternalAccountKey,
+    exe: &ExecutableSource,
+) -> Result<String, RequestError> {
+    if env::var(ALLOW_EXECUTABLES_VAR).ok().as_deref() != Some("1") {
+        return Err(user_error(format!(
+            "Executable-sourced credentials require {}=1",
+            ALLOW_EXECUTABLES_VAR
+        )));
+    }
+    if let Some(ref output_file) = exe.output_file {
+        // An unusable cached output is not an error; the executable is run instead.
+        if let Ok(contents) = fs::read_to_string(output_file) {
+            if let Ok(Some(token)) = executable_token(key, &contents) {
+                return Ok(token);
+            }
+        }
+    }
+
+    let mut args = exe.command.split_whitespace();
+    let program = args
+        .next()
+        .ok_or_else(|| user_error("The credential executable command is empty"))?;
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .env("GOOGLE_EXTERNAL_ACCOUNT_AUDIENCE", &key.audience)
+        .env(
+            "GOOGLE_EXTERNAL_ACCOUNT_TOKEN_TYPE",
+            &key.subject_token_type,
+        )
+        .env("GOOGLE_EXTERNAL_ACCOUNT_INTERACTIVE", "0");
+    if let Some(ref url) = key.service_account_impersonation_url {
+        if let Some(email) = impersonated_email(url) {
+            command.env("GOOGLE_EXTERNAL_ACCOUNT_IMPERSONATED_EMAIL", email);
+        }
+    }
+    if let Some(ref output_file) = exe.output_file {
+        command.env("GOOGLE_EXTERNAL_ACCOUNT_OUTPUT_FILE", output_file);
+    }
+
+    let mut child = command.spawn().map_err(RequestError::LowLevelError)?;
+    // Read stdout while waiting, or an executable writing more than fits into the pipe would
+    // block until it is killed.
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let reader = thread::spawn(move || {
+        let mut output = Vec::new();
+        stdout.read_to_end(&mut output).map(|_| output)
+    });
+    let timeout = exe
+        .timeout_millis
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_EXECUTABLE_TIMEOUT);
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(RequestError::LowLevelError)? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(RequestError::LowLevelError(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("The credential executable timed out after {:?}", timeout),
+            )));
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    let output = reader
+        .join()
+        .unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "The credential executable's output could not be read",
+            ))
+        })
+        .map_err(RequestError::LowLevelError)?;
+    if !status.success() {
+        return Err(user_error(format!(
+            "The credential executable failed with {}",
+            status
+        )));
+    }
+    match executable_token(key, &String::from_utf8_lossy(&output))? {
+        Some(token) => Ok(token),
+        None => Err(RequestError::BadServerResponse(
+            "The credential executable returned an expired token".to_string(),
+        )),
+    }
+}
+
+/// Parse the output of a credential executable. Returns None if the token has expired.
+fn executable_token(
+    key: &ExternalAccountKey,
+    output: &str,
+) -> Result<Option<String>, RequestError> {
+    let response: ExecutableResponse =
+        serde_json::from_str(output).map_err(RequestError::JSONError)?;
+    if response.version != 1 {
+        return Err(RequestError::BadServerResponse(format!(
+            "Unsupported credential executable response version {}",
+            response.version
+        )));
+    }
+    if !response.success {
+        return Err(RequestError::NegativeServerResponse(OAuthError::new(
+            response.code.unwrap_or_default(),
+            response.message,
+        )));
+    }
+    if response.token_type.as_ref() != Some(&key.subject_token_type) {
+        return Err(RequestError::BadServerResponse(format!(
+            "The credential executable returned a token of type {:?}, not {}",
+            response.token_type, key.subject_token_type
+        )));
+    }
+    if let Some(expiration_time) = response.expiration_time {
+        if expiration_time <= chrono::Utc::now().timestamp() {
+            return Ok(None);
+        }
+    }
+    match response.id_token.or(response.saml_response) {
+        Some(token) => Ok(Some(token)),
+        None => Err(RequestError::BadServerResponse(
+            "The credential executable returned no token".to_string(),
+        )),
+    }
+}
+
+/// The email of the service account impersonated through `url`, which ends in
+/// `serviceAccounts/<email>:generateAccessToken`.
+fn impersonated_email(url: &str) -> Option<&str> {
+    let name = url.rsplit('/').next()?;
+    name.split(':').next()
+}
+
+impl<C: 'static + hyper::client::connect::Connect> ExternalAccountAccessImpl<C> {
+    /// Obtain the subject token from the credential source.
+    fn subject_token(&self) -> Box<dyn Future<Item = String, Error = RequestError> + Send> {
+        let key = self.key.clone();
+        let source = &key.credential_source;
+        if let Some(ref environment_id) = source.environment_id {
+            return Box::new(future::err(user_error(format!(
+                "Credential source {} is not supported",
+                environment_id
+            ))));
+        }
+        if let Some(ref url) = source.url {
+            let mut request = hyper::Request::get(url.as_str());
+            for (name, value) in source.headers.iter().flatten() {
+                request.header(name.as_str(), value.as_str());
+            }
+            let request = match request.body(hyper::Body::empty()) {
+                Ok(request) => request,
+                Err(e) => return Box::new(future::err(user_error(e.to_string()))),
+            };
+            let key = self.key.clone();
+            return Box::new(
+                self.client
+                    .request(request)
+                    .map_err(RequestError::ClientError)
+                    .and_then(|response| {
+                        let status = response.status();
+                        response
+                            .into_body()
+                            .concat2()
+                            .map_err(RequestError::ClientError)
+                            .map(move |body| (status, body))
+                    })
+                    .and_then(move |(status, body)| {
+                        let body = String::from_utf8_lossy(&body);
+                        if !status.is_success() {
+                            return Err(RequestError::BadServerResponse(format!(
+                                "Subject token URL returned {}: {}",
+                                status, body
+                            )));
+                        }
+                        parse_subject_token(&body, key.credential_source.format.as_ref())
+                    }),
+            );
+        }
+        if let (None, Some(exe)) = (&source.file, source.executable.clone()) {
+            // The executable may run for a while, so it is waited for on a thread of its own
+            // rather than blocking the executor.
+            let (tx, rx) = oneshot::channel();
+            let key = self.key.clone();
+            thread::spawn(move || {
+                let _ = tx.send(run_executable(&key, &exe));
+            });
+            return Box::new(rx.then(|r| {
+                r.unwrap_or_else(|_| {
+                    Err(RequestError::LowLevelError(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "The credential executable thread panicked",
+                    )))
+                })
+            }));
+        }
+        Box::new(futures::lazy(move || {
+            let source = &key.credential_source;
+            if let Some(ref file) = source.file {
+                let contents = fs::read_to_string(file).map_err(RequestError::LowLevelError)?;
+                parse_subject_token(&contents, source.format.as_ref())
+            } else {
+                Err(user_error(
+                    "The credential source has neither file, url nor executable",
+                ))
+            }
+        }))
+    }
+}
+
+/// Exchange `subject_token` for a Google access token for `scopes` at the STS.
+fn exchange_token<C: 'static + hyper::client::connect::Connect>(
+    client: hyper::Client<C>,
+    key: &ExternalAccountKey,
+    scopes: &[String],
+    subject_token: String,
+) -> impl Future<Item = Token, Error = RequestError> {
+    let scope = if key.service_account_impersonation_url.is_some() {
+        CLOUD_PLATFORM_SCOPE.to_string()
+    } else {
+        scopes.join(" ")
+    };
+    let mut body = form_urlencoded::Serializer::new(String::new());
+    body.extend_pairs(vec![
+        ("grant_type", TOKEN_EXCHANGE_GRANT_TYPE),
+        ("audience", key.audience.as_str()),
+        ("scope", scope.as_str()),
+        ("requested_token_type", ACCESS_TOKEN_TYPE),
+        ("subject_token", subject_token.as_str()),
+        ("subject_token_type", key.subject_token_type.as_str()),
+    ]);
+    if let Some(ref project) = key.workforce_pool_user_project {
+        body.append_pair(
+            "options",
+            &serde_json::json!({ "userProject": project }).to_string(),
+        );
+    }
+    let request = hyper::Request::post(key.token_url.as_str())
+        .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(hyper::Body::from(body.finish()))
+        .unwrap();
+    client
+        .request(request)
+        .map_err(RequestError::ClientError)
+        .and_then(|response| {
+            let status = response.status();
+            response
+                .into_body()
+                .concat2()
+                .map(move |body| (status, body))
+                .map_err(RequestError::ClientError)
+        })
+        .and_then(|(status, body)| {
+            let s = String::from_utf8_lossy(&body);
+            if status.is_success() {
+                return serde_json::from_str::<StsResponse>(&s).map_err(RequestError::JSONError);
+            }
+            match serde_json::from_str::<JsonError>(&s) {
+                Ok(jse) => Err(RequestError::NegativeServerResponse(jse.into())),
+                Err(_) => Err(RequestError::BadServerResponse(format!(
+                    "Token exchange failed with {}: {}",
+                    status, s
+                ))),
+            }
+        })
+        .map(|response| Token {
+            access_token: response.access_token,
+            token_type: response.token_type,
+            refresh_token: String::new(),
+            expires_in: response.expires_in,
+            id_token: None,
+            scope: None,
+            extra: extra_parameters(response.extra),
+            refresh_token_expires_in: None,
+            refresh_token_expires_in_timestamp: None,
+            expires_in_timestamp: response
+                .expires_in
+                .map(|e| chrono::Utc::now().timestamp() + e),
+        })
+}
+
+impl<C: 'static> GetToken for ExternalAccountAccessImpl<C>
+where
+    C: hyper::client::connect::Connect,
+{
+    fn token<I, T>(
+        &mut self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let (hash, scopes) = hash_scopes(scopes);
+        let scope_refs = scopes.iter().map(String::as_str).collect();
+        if let Ok(Some(token)) = self.cache.lock().unwrap().get(hash, &scope_refs) {
+            if !token.expired() {
+                return Box::new(future::ok(token));
+            }
+        }
+
+        let client = self.client.clone();
+        let key = self.key.clone();
+        let scopes = Arc::new(scopes);
+        let exchange_scopes = scopes.clone();
+        let exchanged = self.subject_token().and_then(move |subject_token| {
+            exchange_token(client, &key, &exchange_scopes, subject_token)
+        });
+
+        let client = self.client.clone();
+        let key = self.key.clone();
+        let impersonation_scopes = scopes.clone();
+        let token = exchanged.and_then(
+            move |sts_token| -> Box<dyn Future<Item = Token, Error = RequestError> + Send> {
+                let url = match key.service_account_impersonation_url {
+                    Some(ref url) => url.clone(),
+                    None => return Box::new(future::ok(sts_token)),
+                };
+                let lifetime = key
+                    .service_account_impersonation
+                    .as_ref()
+                    .and_then(|o| o.token_lifetime_seconds)
+                    .map(|l| format!("{}s", l));
+                let body = serde_json::to_string(&GenerateAccessTokenRequest {
+                    delegates: &[],
+                    scope: &impersonation_scopes,
+                    lifetime: &lifetime,
+                })
+                .unwrap();
+                Box::new(impersonation::request_token(client, url, body, sts_token))
+            },
+        );
+
+        let cache = self.cache.clone();
+        Box::new(token.map(move |token| {
+            let scope_refs = scopes.iter().map(String::as_str).collect();
+            let _ = cache
+                .lock()
+                .unwrap()
+                .set(hash, &scope_refs, Some(token.clone()));
+            token
+        }))
+    }
+
+    /// Returns an empty ApplicationSecret as external account tokens can't be refreshed (they
+    /// are simply reissued).
+    fn application_secret(&self) -> ApplicationSecret {
+        Default::default()
+    }
+
+    fn api_key(&mut self) -> Option<String> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::external_account_key_from_file;
+
+    use mockito::{self, mock, Matcher};
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir()
+            .join(format!(
+                "yup-oauth2-external-{}-{}",
+                name,
+                std::process::id()
+            ))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn config(credential_source: serde_json::Value, impersonate: bool) -> ExternalAccountKey {
+        let mut config = serde_json::json!({
+            "type": "external_account",
+            "audience": "//iam.googleapis.com/projects/1/locations/global/workloadIdentityPools/pool/providers/github",
+            "subject_token_type": "urn:ietf:params:oauth:token-type:jwt",
+            "token_url": format!("{}/sts/v1/token", mockito::server_url()),
+            "credential_source": credential_source,
+        });
+        if impersonate {
+            config["service_account_impersonation_url"] = format!(
+                "{}/v1/projects/-/serviceAccounts/sa@p.iam.gserviceaccount.com:generateAccessToken",
+                mockito::server_url()
+            )
+            .into();
+            config["service_account_impersonation"] =
+                serde_json::json!({"token_lifetime_seconds": 600});
+        }
+        let path = temp_path("config");
+        fs::write(&path, config.to_string()).unwrap();
+        let key = external_account_key_from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        key
+    }
+
+    fn sts_mock(subject_token: &str, scope: &str) -> mockito::Mock {
+        mock("POST", "/sts/v1/token")
+            .match_body(Matcher::Regex(format!(
+                "^grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Atoken-exchange\
+                 &audience=[^&]+&scope={}&requested_token_type=[^&]+\
+                 &subject_token={}&subject_token_type=[^&]+$",
+                scope, subject_token
+            )))
+            .with_status(200)
+            .with_body(
+                r#"{"access_token": "sts-token", "issued_token_type": "urn:ietf:params:oauth:token-type:access_token", "token_type": "Bearer", "expires_in": 3600}"#,
+            )
+            .expect(1)
+            .create()
+    }
+
+    #[test]
+    fn test_file_sourced() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let token_file = temp_path("token");
+        fs::write(&token_file, "file-subject-token\n").unwrap();
+        let key = config(serde_json::json!({ "file": token_file }), false);
+        let _sts = sts_mock("file-subject-token", "scope%2Fa");
+
+        let mut acc = ExternalAccountAccess::new(key).build();
+        for _ in 0..2 {
+            let tok = rt.block_on(acc.token(vec!["scope/a"])).unwrap();
+            assert_eq!("sts-token", tok.access_token);
+        }
+        _sts.assert();
+
+        // Error responses that are neither JSON nor UTF-8 are reported with their status.
+        let _sts = mock("POST", "/sts/v1/token")
+            .match_body(Matcher::Regex("scope=scope%2Fb&".to_string()))
+            .with_status(503)
+            .with_body(&b"\xff unavailable"[..])
+            .create();
+        match rt.block_on(acc.token(vec!["scope/b"])) {
+            Err(RequestError::BadServerResponse(msg)) => assert!(msg.contains("503")),
+            r => panic!("unexpected result {:?}", r),
+        }
+        fs::remove_file(&token_file).unwrap();
+    }
+
+    #[test]
+    fn test_url_sourced_with_impersonation() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let _subject = mock("GET", "/subject-token")
+            .match_header("metadata", "True")
+            .with_status(200)
+            .with_body(r#"{"value": "url-subject-token"}"#)
+            .expect(1)
+            .create();
+        let _sts = sts_mock(
+            "url-subject-token",
+            "https%3A%2F%2Fwww.googleapis.com%2Fauth%2Fcloud-platform",
+        );
+        let expire_time = (chrono::Utc::now() + chrono::Duration::minutes(10)).to_rfc3339();
+        let _iam = mock(
+            "POST",
+            "/v1/projects/-/serviceAccounts/sa@p.iam.gserviceaccount.com:generateAccessToken",
+        )
+        .match_header("authorization", "Bearer sts-token")
+        .match_body(Matcher::Json(
+            serde_json::json!({"scope": ["scope/b"], "lifetime": "600s"}),
+        ))
+        .with_status(200)
+        .with_body(format!(
+            r#"{{"accessToken": "sa-token", "expireTime": "{}"}}"#,
+            expire_time
+        ))
+        .expect(1)
+        .create();
+
+        let key = config(
+            serde_json::json!({
+                "url": format!("{}/subject-token", mockito::server_url()),
+                "headers": {"Metadata": "True"},
+                "format": {"type": "json", "subject_token_field_name": "value"},
+            }),
+            true,
+        );
+        let mut acc = ExternalAccountAccess::new(key).build();
+        let tok = rt.block_on(acc.token(vec!["scope/b"])).unwrap();
+        assert_eq!("sa-token", tok.access_token);
+        _subject.assert();
+        _sts.assert();
+        _iam.assert();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_executable_sourced() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let script = temp_path("executable.sh");
+        let expiration = chrono::Utc::now().timestamp() + 3600;
+        // The output is larger than a pipe's buffer.
+        fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\nprintf '{{\"padding\": \"'\nhead -c 100000 /dev/zero | tr '\\000' x\necho '\", \"version\": 1, \"success\": true, \"token_type\": \"'$GOOGLE_EXTERNAL_ACCOUNT_TOKEN_TYPE'\", \"id_token\": \"exe-subject-token\", \"expiration_time\": {}}}'\n",
+                expiration
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o700)).unwrap();
+        let key = config(
+            serde_json::json!({ "executable": { "command": script, "timeout_millis": 5000 } }),
+            false,
+        );
+
+        env::remove_var(ALLOW_EXECUTABLES_VAR);
+        let mut acc = ExternalAccountAccess::new(key.clone()).build();
+        match rt.block_on(acc.token(vec!["scope/c"])) {
+            Err(RequestError::UserError(msg)) => assert!(msg.contains(ALLOW_EXECUTABLES_VAR)),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        env::set_var(ALLOW_EXECUTABLES_VAR, "1");
+        let _sts = sts_mock("exe-subject-token", "scope%2Fc");
+        let mut acc = ExternalAccountAccess::new(key).build();
+        let tok = rt.block_on(acc.token(vec!["scope/c"])).unwrap();
+        assert_eq!("sts-token", tok.access_token);
+        _sts.assert();
+        env::remove_var(ALLOW_EXECUTABLES_VAR);
+        fs::remove_file(&script).unwrap();
+    }
+
+    #[test]
+    fn test_parse_subject_token() {
+        let json = SubjectTokenFormat {
+            format_type: "json".to_string(),
+            subject_token_field_name: Some("access_token".to_string()),
+        };
+        assert_eq!(
+            "abc",
+            parse_subject_token(r#"{"access_token": "abc"}"#, Some(&json)).unwrap()
+        );
+        assert!(parse_subject_token(r#"{"other": "abc"}"#, Some(&json)).is_err());
+        assert_eq!("abc", parse_subject_token(" abc\n", None).unwrap());
+        assert_eq!(
+            Some("sa@p.iam.gserviceaccount.com"),
+            impersonated_email("https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts/sa@p.iam.gserviceaccount.com:generateAccessToken")
+        );
+    }
+}
@@ -13,6 +13,7 @@ use std::fs;
 use std::io::{self, Read};
 use std::path::Path;
 
+use crate::external_account::ExternalAccountKey;
 use crate::service_account::ServiceAccountKey;
 use crate::types::{ApplicationSecret, ConsoleApplicationSecret};
 
@@ -61,3 +62,21 @@ pub fn service_account_key_from_file<S: AsRef<Path>>(path: S) -> io::Result<Serv
         Ok(decoded) => Ok(decoded),
     }
 }
+
+/// Read an `external_account` configuration for Workload Identity Federation from a JSON file.
+pub fn external_account_key_from_file<S: AsRef<Path>>(path: S) -> io::Result<ExternalAccountKey> {
+    let key = fs::read_to_string(path)?;
+    match serde_json::from_str::<ExternalAccountKey>(&key) {
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}", e))),
+        Ok(ref decoded) if decoded.key_type.as_deref() != Some("external_account") => {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Not an external_account configuration: type {:?}",
+                    decoded.key_type
+                ),
+            ))
+        }
+        Ok(decoded) => Ok(decoded),
+    }
+}
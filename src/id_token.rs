@@ -0,0 +1,102 @@
+//! This module decodes OpenID Connect ID tokens, as returned alongside access tokens when the
+//! `openid` scope is requested.
+//!
+//! Decoding does not verify the token's signature; tokens received directly from the token
+//! endpoint over TLS can be trusted without it
+//! ([OpenID Connect Core, 3.1.3.7](https://openid.net/specs/openid-connect-core-1_0.html#IDTokenValidation)).
+
+use std::collections::HashMap;
+use std::io;
+
+use serde::{Deserialize, Deserializer};
+
+/// The claims of an ID token.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct IdTokenClaims {
+    /// The issuer, e.g. `https://accounts.google.com`.
+    pub iss: String,
+    /// The identifier of the user at the issuer.
+    pub sub: String,
+    /// The audiences, usually just the client ID.
+    #[serde(deserialize_with = "string_or_list")]
+    pub aud: Vec<String>,
+    /// Expiry as seconds since epoch.
+    pub exp: i64,
+    /// Issue time as seconds since epoch.
+    pub iat: i64,
+    pub nonce: Option<String>,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    pub name: Option<String>,
+    /// All other claims.
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+/// `aud` is either a single string or a list of strings.
+fn string_or_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        String(String),
+        List(Vec<String>),
+    }
+    Ok(match StringOrList::deserialize(deserializer)? {
+        StringOrList::String(s) => vec![s],
+        StringOrList::List(l) => l,
+    })
+}
+
+impl IdTokenClaims {
+    /// Decode the claims of `id_token` without verifying its signature.
+    pub fn decode(id_token: &str) -> Result<IdTokenClaims, io::Error> {
+        let payload = id_token
+            .split('.')
+            .nth(1)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "ID token is not a JWT"))?;
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        serde_json::from_slice(&payload).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jwt(claims: &str) -> String {
+        format!(
+            "e30.{}.c2ln",
+            base64::encode_config(claims, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    #[test]
+    fn test_decode_claims() {
+        let claims = IdTokenClaims::decode(&jwt(
+            r#"{"iss": "https://accounts.google.com", "sub": "1234", "aud": "client",
+                "exp": 1500, "iat": 900, "email": "user@example.com", "email_verified": true,
+                "nonce": "n-0S6", "hd": "example.com"}"#,
+        ))
+        .unwrap();
+        assert_eq!("1234", claims.sub);
+        assert_eq!(vec!["client".to_string()], claims.aud);
+        assert_eq!(Some("user@example.com"), claims.email.as_deref());
+        assert_eq!(Some(true), claims.email_verified);
+        assert_eq!(Some("n-0S6"), claims.nonce.as_deref());
+        assert_eq!(
+            Some(&serde_json::json!("example.com")),
+            claims.extra.get("hd")
+        );
+
+        let claims = IdTokenClaims::decode(&jwt(
+            r#"{"iss": "issuer", "sub": "1234", "aud": ["a", "b"], "exp": 1500, "iat": 900}"#,
+        ))
+        .unwrap();
+        assert_eq!(vec!["a".to_string(), "b".to_string()], claims.aud);
+        assert_eq!(None, claims.email);
+
+        assert!(IdTokenClaims::decode("not a jwt").is_err());
+        assert!(IdTokenClaims::decode(&jwt(r#"{"sub": "1234"}"#)).is_err());
+    }
+}
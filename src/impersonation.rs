@@ -0,0 +1,401 @@
+//! This module provides a token source (`GetToken`) that impersonates a service account: it uses
+//! the tokens of another token source (the source credential) to obtain short-lived tokens for
+//! the target service account from the IAM Credentials API.
+//!
+//! The source credential needs the `roles/iam.serviceAccountTokenCreator` role on the target
+//! service account, or on the first of the `delegates` if a delegation chain is used.
+//!
+//! Resources:
+//! - [Creating short-lived service account
+//!   credentials](https://cloud.google.com/iam/docs/creating-short-lived-service-account-credentials)
+//! - [generateAccessToken](https://cloud.google.com/iam/docs/reference/credentials/rest/v1/projects.serviceAccounts/generateAccessToken)
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::authenticator::{DefaultHyperClient, HyperClientBuilder};
+use crate::storage::{hash_scopes, MemoryStorage, TokenStorage};
+use crate::types::{
+    extra_parameters, ApplicationSecret, GetToken, OAuthError, RequestError, Token,
+};
+
+use futures::stream::Stream;
+use futures::{future, prelude::*};
+use hyper::header;
+
+/// The default endpoint of the IAM Credentials API.
+pub const GOOGLE_IAM_CREDENTIALS_URL: &str = "https://iamcredentials.googleapis.com";
+/// The scope requested from the source credential.
+const IAM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+
+/// Builds a token source (`GetToken`) yielding access tokens for `target_principal`, a service
+/// account, by impersonating it with the tokens of `source`. Tokens are cached and requested
+/// again shortly before they expire.
+pub struct ImpersonatedServiceAccount<S, C> {
+    source: S,
+    client: C,
+    target_principal: String,
+    delegates: Vec<String>,
+    lifetime: Option<Duration>,
+    iam_credentials_url: String,
+}
+
+impl<S: GetToken> ImpersonatedServiceAccount<S, DefaultHyperClient> {
+    /// Impersonate the service account with email `target_principal`, using the tokens of
+    /// `source`.
+    pub fn new<T: Into<String>>(source: S, target_principal: T) -> Self {
+        ImpersonatedServiceAccount {
+            source,
+            client: DefaultHyperClient,
+            target_principal: target_principal.into(),
+            delegates: vec![],
+            lifetime: None,
+            iam_credentials_url: GOOGLE_IAM_CREDENTIALS_URL.to_string(),
+        }
+    }
+}
+
+impl<S, C> ImpersonatedServiceAccount<S, C>
+where
+    S: GetToken,
+    C: HyperClientBuilder,
+    C::Connector: 'static,
+{
+    /// Use the provided hyper client.
+    pub fn hyper_client<NewC: HyperClientBuilder>(
+        self,
+        hyper_client: NewC,
+    ) -> ImpersonatedServiceAccount<S, NewC> {
+        ImpersonatedServiceAccount {
+            source: self.source,
+            client: hyper_client,
+            target_principal: self.target_principal,
+            delegates: self.delegates,
+            lifetime: self.lifetime,
+            iam_credentials_url: self.iam_credentials_url,
+        }
+    }
+
+    /// Impersonate the target through a delegation chain: the source credential impersonates the
+    /// first of `delegates`, which impersonates the next one, and so on; the last one
+    /// impersonates the target. Delegates are given as service account emails.
+    pub fn delegates(self, delegates: Vec<String>) -> Self {
+        ImpersonatedServiceAccount { delegates, ..self }
+    }
+
+    /// Request tokens valid for `lifetime`. The IAM Credentials API defaults to one hour, and
+    /// allows up to 12 hours if the organization permits it.
+    pub fn lifetime(self, lifetime: Duration) -> Self {
+        ImpersonatedServiceAccount {
+            lifetime: Some(lifetime),
+            ..self
+        }
+    }
+
+    /// Use the IAM Credentials API at the provided URL instead of `GOOGLE_IAM_CREDENTIALS_URL`.
+    pub fn iam_credentials_url<U: Into<String>>(self, url: U) -> Self {
+        ImpersonatedServiceAccount {
+            iam_credentials_url: url.into(),
+            ..self
+        }
+    }
+
+    /// Build the configured token source.
+    pub fn build(self) -> impl GetToken {
+        ImpersonatedServiceAccountImpl {
+            source: self.source,
+            client: self.client.build_hyper_client(),
+            url: format!(
+                "{}/v1/{}:generateAccessToken",
+                self.iam_credentials_url.trim_end_matches('/'),
+                resource_name(&self.target_principal)
+            ),
+            delegates: self.delegates.iter().map(|d| resource_name(d)).collect(),
+            lifetime: self.lifetime.map(|l| format!("{}s", l.as_secs())),
+            cache: Arc::new(Mutex::new(MemoryStorage::new())),
+        }
+    }
+}
+
+/// The resource name of the service account with email `email`.
+fn resource_name(email: &str) -> String {
+    format!("projects/-/serviceAccounts/{}", email)
+}
+
+struct ImpersonatedServiceAccountImpl<S, C> {
+    source: S,
+    client: hyper::Client<C, hyper::Body>,
+    url: String,
+    delegates: Vec<String>,
+    lifetime: Option<String>,
+    cache: Arc<Mutex<MemoryStorage>>,
+}
+
+/// Body of a generateAccessToken request.
+#[derive(Serialize, Debug)]
+pub(crate) struct GenerateAccessTokenRequest<'a> {
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub delegates: &'a [String],
+    pub scope: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lifetime: &'a Option<String>,
+}
+
+/// Body of a successful generateAccessToken response.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GenerateAccessTokenResponse {
+    access_token: String,
+    expire_time: String,
+    #[serde(flatten)]
+    extra: HashMap<String, serde_json::Value>,
+}
+
+/// Google API error response.
+#[derive(Deserialize, Debug)]
+struct ErrorResponse {
+    error: ErrorDetails,
+}
+
+#[derive(Deserialize, Debug)]
+struct ErrorDetails {
+    message: String,
+    status: String,
+}
+
+impl GenerateAccessTokenResponse {
+    fn into_token(self) -> Result<Token, RequestError> {
+        let expiry = chrono::DateTime::parse_from_rfc3339(&self.expire_time).map_err(|e| {
+            RequestError::BadServerResponse(format!(
+                "Invalid expireTime {}: {}",
+                self.expire_time, e
+            ))
+        })?;
+        let expiry = expiry.timestamp();
+        Ok(Token {
+            access_token: self.access_token,
+            token_type: "Bearer".to_string(),
+            refresh_token: String::new(),
+            expires_in: Some(expiry - chrono::Utc::now().timestamp()),
+            id_token: None,
+            scope: None,
+            extra: extra_parameters(self.extra),
+            refresh_token_expires_in: None,
+            refresh_token_expires_in_timestamp: None,
+            expires_in_timestamp: Some(expiry),
+        })
+    }
+}
+
+/// Send a generateAccessToken request with `body` to `url`, authorized by `source_token`.
+pub(crate) fn request_token<C: 'static + hyper::client::connect::Connect>(
+    client: hyper::Client<C>,
+    url: String,
+    body: String,
+    source_token: Token,
+) -> impl Future<Item = Token, Error = RequestError> {
+    let request = hyper::Request::post(url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(
+            header::AUTHORIZATION,
+            format!("Bearer {}", source_token.access_token),
+        )
+        .body(hyper::Body::from(body))
+        .unwrap();
+    client
+        .request(request)
+        .map_err(RequestError::ClientError)
+        .and_then(|response| {
+            let status = response.status();
+            response
+                .into_body()
+                .concat2()
+                .map(move |body| (status, body))
+                .map_err(RequestError::ClientError)
+        })
+        .and_then(|(status, body)| {
+            let s = String::from_utf8_lossy(&body);
+            if status.is_success() {
+                return serde_json::from_str::<GenerateAccessTokenResponse>(&s)
+                    .map_err(RequestError::JSONError);
+            }
+            match serde_json::from_str::<ErrorResponse>(&s) {
+                Ok(e) => Err(RequestError::NegativeServerResponse(OAuthError::new(
+                    e.error.status,
+                    Some(e.error.message),
+                ))),
+                Err(_) => Err(RequestError::BadServerResponse(format!(
+                    "Token request failed with {}: {}",
+                    status, s
+                ))),
+            }
+        })
+        .and_then(GenerateAccessTokenResponse::into_token)
+}
+
+impl<S, C> GetToken for ImpersonatedServiceAccountImpl<S, C>
+where
+    S: GetToken,
+    C: 'static + hyper::client::connect::Connect,
+{
+    fn token<I, T>(
+        &mut self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let (hash, scopes) = hash_scopes(scopes);
+        let scope_refs = scopes.iter().map(String::as_str).collect();
+        if let Ok(Some(token)) = self.cache.lock().unwrap().get(hash, &scope_refs) {
+            if !token.expired() {
+                return Box::new(future::ok(token));
+            }
+        }
+
+        let body = serde_json::to_string(&GenerateAccessTokenRequest {
+            delegates: &self.delegates,
+            scope: &scopes,
+            lifetime: &self.lifetime,
+        })
+        .unwrap();
+        let client = self.client.clone();
+        let url = self.url.clone();
+        let cache = self.cache.clone();
+        Box::new(
+            self.source
+                .token(vec![IAM_SCOPE])
+                .and_then(move |source_token| request_token(client, url, body, source_token))
+                .map(move |token| {
+                    let scope_refs = scopes.iter().map(String::as_str).collect();
+                    let _ = cache
+                        .lock()
+                        .unwrap()
+                        .set(hash, &scope_refs, Some(token.clone()));
+                    token
+                }),
+        )
+    }
+
+    /// Returns an empty ApplicationSecret as impersonated tokens can't be refreshed (they are
+    /// simply reissued).
+    fn application_secret(&self) -> ApplicationSecret {
+        Default::default()
+    }
+
+    fn api_key(&mut self) -> Option<String> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mockito::{self, mock};
+
+    /// A source credential that counts the tokens it hands out.
+    struct FakeSource(Arc<Mutex<usize>>);
+
+    impl GetToken for FakeSource {
+        fn token<I, T>(
+            &mut self,
+            scopes: I,
+        ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+        where
+            T: Into<String>,
+            I: IntoIterator<Item = T>,
+        {
+            let scopes: Vec<String> = scopes.into_iter().map(Into::into).collect();
+            assert_eq!(vec![IAM_SCOPE.to_string()], scopes);
+            *self.0.lock().unwrap() += 1;
+            Box::new(future::ok(Token {
+                access_token: "source-token".to_string(),
+                token_type: "Bearer".to_string(),
+                refresh_token: String::new(),
+                expires_in: None,
+                id_token: None,
+                scope: None,
+                extra: HashMap::new(),
+                refresh_token_expires_in: None,
+                refresh_token_expires_in_timestamp: None,
+                expires_in_timestamp: None,
+            }))
+        }
+
+        fn application_secret(&self) -> ApplicationSecret {
+            Default::default()
+        }
+
+        fn api_key(&mut self) -> Option<String> {
+            None
+        }
+    }
+
+    #[test]
+    fn test_impersonation() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let path =
+            "/v1/projects/-/serviceAccounts/target@p.iam.gserviceaccount.com:generateAccessToken";
+        let expire_time = (chrono::Utc::now() + chrono::Duration::hours(1)).to_rfc3339();
+        let _m = mock("POST", path)
+            .match_header("authorization", "Bearer source-token")
+            .match_body(mockito::Matcher::Json(serde_json::json!({
+                "delegates": ["projects/-/serviceAccounts/middle@p.iam.gserviceaccount.com"],
+                "scope": ["scope/a", "scope/b"],
+                "lifetime": "600s",
+            })))
+            .with_status(200)
+            .with_body(format!(
+                r#"{{"accessToken": "impersonated", "expireTime": "{}"}}"#,
+                expire_time
+            ))
+            .expect(1)
+            .create();
+
+        let issued = Arc::new(Mutex::new(0));
+        let mut acc = ImpersonatedServiceAccount::new(
+            FakeSource(issued.clone()),
+            "target@p.iam.gserviceaccount.com",
+        )
+        .delegates(vec!["middle@p.iam.gserviceaccount.com".to_string()])
+        .lifetime(Duration::from_secs(600))
+        .iam_credentials_url(mockito::server_url())
+        .build();
+        for _ in 0..2 {
+            let tok = rt.block_on(acc.token(vec!["scope/b", "scope/a"])).unwrap();
+            assert_eq!("impersonated", tok.access_token);
+            assert!(!tok.expired());
+        }
+        _m.assert();
+        assert_eq!(1, *issued.lock().unwrap());
+
+        // Errors are reported with their status.
+        let _m = mock("POST", path)
+            .with_status(403)
+            .with_body(
+                r#"{"error": {"code": 403, "message": "Permission denied", "status": "PERMISSION_DENIED"}}"#,
+            )
+            .create();
+        match rt.block_on(acc.token(vec!["scope/c"])) {
+            Err(RequestError::NegativeServerResponse(e)) => {
+                assert_eq!("PERMISSION_DENIED", e.code.as_str());
+                assert_eq!(Some("Permission denied"), e.description.as_deref());
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // Other error responses don't need to be JSON, or even UTF-8.
+        drop(_m);
+        let _m = mock("POST", path)
+            .with_status(502)
+            .with_body(&b"\xff bad gateway"[..])
+            .create();
+        match rt.block_on(acc.token(vec!["scope/d"])) {
+            Err(RequestError::BadServerResponse(msg)) => assert!(msg.contains("502")),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+}
@@ -2,6 +2,7 @@
 //
 // Refer to the project root for licensing information.
 //
+use std::collections::HashMap;
 use std::convert::AsRef;
 use std::sync::{Arc, Mutex};
 
@@ -14,7 +15,10 @@ use url::form_urlencoded;
 use url::percent_encoding::{percent_encode, QUERY_ENCODE_SET};
 
 use crate::authenticator_delegate::{DefaultFlowDelegate, FlowDelegate};
-use crate::types::{ApplicationSecret, GetToken, RequestError, Token};
+use crate::id_token::IdTokenClaims;
+use crate::types::{
+    extra_parameters, ApplicationSecret, GetToken, OAuthError, RequestError, Token,
+};
 
 const OOB_REDIRECT_URI: &'static str = "urn:ietf:wg:oauth:2.0:oob";
 
@@ -26,6 +30,7 @@ fn build_authentication_request_url<'a, T, I>(
     client_id: &str,
     scopes: I,
     redirect_uri: Option<String>,
+    nonce: Option<&str>,
 ) -> String
 where
     T: AsRef<str> + 'a,
@@ -41,7 +46,7 @@ where
     scopes_string.pop();
 
     url.push_str(auth_uri);
-    vec![
+    let mut params = vec![
         format!("?scope={}", scopes_string),
         format!("&access_type=offline"),
         format!(
@@ -50,9 +55,13 @@ where
         ),
         format!("&response_type=code"),
         format!("&client_id={}", client_id),
-    ]
-    .into_iter()
-    .fold(url, |mut u, param| {
+    ];
+    if let Some(nonce) = nonce {
+        // The nonce is chosen by the caller, so it may contain characters like `&`.
+        let nonce: String = form_urlencoded::byte_serialize(nonce.as_bytes()).collect();
+        params.push(format!("&nonce={}", nonce));
+    }
+    params.into_iter().fold(url, |mut u, param| {
         u.push_str(&percent_encode(param.as_ref(), QUERY_ENCODE_SET).to_string());
         u
     })
@@ -85,6 +94,7 @@ pub struct InstalledFlowImpl<FD: FlowDelegate, C: hyper::client::connect::Connec
     client: hyper::client::Client<C, hyper::Body>,
     fd: FD,
     appsecret: ApplicationSecret,
+    nonce: Option<String>,
 }
 
 /// cf. https://developers.google.com/identity/protocols/OAuth2InstalledApp#choosingredirecturi
@@ -108,6 +118,7 @@ pub struct InstalledFlow<FD: FlowDelegate> {
     method: InstalledFlowReturnMethod,
     flow_delegate: FD,
     appsecret: ApplicationSecret,
+    nonce: Option<String>,
 }
 
 impl InstalledFlow<DefaultFlowDelegate> {
@@ -120,6 +131,7 @@ impl InstalledFlow<DefaultFlowDelegate> {
             method,
             flow_delegate: DefaultFlowDelegate,
             appsecret: secret,
+            nonce: None,
         }
     }
 }
@@ -134,6 +146,16 @@ where
             method: self.method,
             flow_delegate: delegate,
             appsecret: self.appsecret,
+            nonce: self.nonce,
+        }
+    }
+
+    /// Send `nonce` with the authorization request. When the `openid` scope is requested, the
+    /// ID token must contain the same nonce, or the token is rejected.
+    pub fn nonce<N: Into<String>>(self, nonce: N) -> InstalledFlow<FD> {
+        InstalledFlow {
+            nonce: Some(nonce.into()),
+            ..self
         }
     }
 }
@@ -150,9 +172,14 @@ where
             method: self.method,
             fd: self.flow_delegate,
             appsecret: self.appsecret,
+            nonce: self.nonce,
             client,
         }
     }
+
+    fn application_secret(&self) -> Option<&ApplicationSecret> {
+        Some(&self.appsecret)
+    }
 }
 
 impl<'c, FD: 'static + FlowDelegate + Clone + Send, C: 'c + hyper::client::connect::Connect>
@@ -191,11 +218,19 @@ impl<'c, FD: 'static + FlowDelegate + Clone + Send, C: 'c + hyper::client::conne
         let client = self.client.clone();
         let (appsecclone, appsecclone2) = (self.appsecret.clone(), self.appsecret.clone());
         let auth_delegate = self.fd.clone();
+        let (nonce, nonce2) = (self.nonce.clone(), self.nonce.clone());
+        let openid_requested = scopes.iter().any(|scope| scope == "openid");
         server
             .into_future()
             // First: Obtain authorization code from user.
             .and_then(move |server| {
-                Self::ask_authorization_code(server, auth_delegate, &appsecclone, scopes.iter())
+                Self::ask_authorization_code(
+                    server,
+                    auth_delegate,
+                    &appsecclone,
+                    scopes.iter(),
+                    nonce,
+                )
             })
             // Exchange the authorization code provided by Google/the provider for a refresh and an
             // access token.
@@ -225,10 +260,10 @@ impl<'c, FD: 'static + FlowDelegate + Clone + Send, C: 'c + hyper::client::conne
                             }
                             Ok(tok) => {
                                 if tok.error.is_some() {
-                                    Err(RequestError::NegativeServerResponse(
+                                    Err(RequestError::NegativeServerResponse(OAuthError::new(
                                         tok.error.unwrap(),
                                         tok.error_description,
-                                    ))
+                                    )))
                                 } else {
                                     Ok(tok)
                                 }
@@ -237,24 +272,40 @@ impl<'c, FD: 'static + FlowDelegate + Clone + Send, C: 'c + hyper::client::conne
                     })
             })
             // Return the combined token.
-            .and_then(|tokens| {
+            .and_then(move |tokens| {
                 // Successful response
                 if tokens.access_token.is_some() {
+                    match (nonce2, tokens.id_token.as_ref()) {
+                        (Some(nonce), Some(id_token)) => check_nonce(id_token, &nonce)?,
+                        // Without the ID token, the nonce can't be checked.
+                        (Some(_), None) if openid_requested => {
+                            return Err(RequestError::BadServerResponse(
+                                "No ID token was returned, so its nonce can't be checked"
+                                    .to_string(),
+                            ))
+                        }
+                        _ => {}
+                    }
                     let mut token = Token {
                         access_token: tokens.access_token.unwrap(),
                         refresh_token: tokens.refresh_token.unwrap(),
                         token_type: tokens.token_type.unwrap(),
                         expires_in: tokens.expires_in,
+                        id_token: tokens.id_token,
+                        scope: tokens.scope,
+                        extra: extra_parameters(tokens.extra),
+                        refresh_token_expires_in: tokens.refresh_token_expires_in,
+                        refresh_token_expires_in_timestamp: None,
                         expires_in_timestamp: None,
                     };
 
                     token.set_expiry_absolute();
                     Ok(token)
                 } else {
-                    Err(RequestError::NegativeServerResponse(
+                    Err(RequestError::NegativeServerResponse(OAuthError::new(
                         tokens.error.unwrap(),
                         tokens.error_description,
-                    ))
+                    )))
                 }
             })
     }
@@ -264,6 +315,7 @@ impl<'c, FD: 'static + FlowDelegate + Clone + Send, C: 'c + hyper::client::conne
         mut auth_delegate: FD,
         appsecret: &ApplicationSecret,
         scopes: S,
+        nonce: Option<String>,
     ) -> Box<dyn Future<Item = String, Error = RequestError> + Send>
     where
         T: AsRef<str> + 'a,
@@ -275,6 +327,7 @@ impl<'c, FD: 'static + FlowDelegate + Clone + Send, C: 'c + hyper::client::conne
                 &appsecret.client_id,
                 scopes,
                 auth_delegate.redirect_uri(),
+                nonce.as_deref(),
             );
             Box::new(
                 auth_delegate
@@ -306,6 +359,7 @@ impl<'c, FD: 'static + FlowDelegate + Clone + Send, C: 'c + hyper::client::conne
                 auth_delegate
                     .redirect_uri()
                     .or_else(|| Some(format!("http://localhost:{}", server.port))),
+                nonce.as_deref(),
             );
             Box::new(
                 auth_delegate
@@ -351,15 +405,35 @@ impl<'c, FD: 'static + FlowDelegate + Clone + Send, C: 'c + hyper::client::conne
     }
 }
 
+/// Rejects an ID token that was not issued in response to our authorization request.
+fn check_nonce(id_token: &str, nonce: &str) -> Result<(), RequestError> {
+    let claims = IdTokenClaims::decode(id_token)
+        .map_err(|e| RequestError::BadServerResponse(format!("Malformed ID token: {}", e)))?;
+    if claims.nonce.as_deref() == Some(nonce) {
+        Ok(())
+    } else {
+        Err(RequestError::BadServerResponse(format!(
+            "ID token nonce {:?} doesn't match the requested nonce",
+            claims.nonce
+        )))
+    }
+}
+
 #[derive(Deserialize)]
 struct JSONTokenResponse {
     access_token: Option<String>,
     refresh_token: Option<String>,
     token_type: Option<String>,
     expires_in: Option<i64>,
+    refresh_token_expires_in: Option<i64>,
+    id_token: Option<String>,
+    scope: Option<String>,
 
     error: Option<String>,
     error_description: Option<String>,
+
+    #[serde(flatten)]
+    extra: HashMap<String, serde_json::Value>,
 }
 
 struct InstalledFlowServer {
@@ -661,7 +735,7 @@ mod tests {
         {
             let _m = mock("POST", "/token")
             .match_body(mockito::Matcher::Regex(".*code=authorizationcode.*client_id=9022167.*".to_string()))
-            .with_body(r#"{"access_token": "accesstoken", "refresh_token": "refreshtoken", "token_type": "Bearer", "expires_in": 12345678}"#)
+            .with_body(r#"{"access_token": "accesstoken", "refresh_token": "refreshtoken", "token_type": "Bearer", "expires_in": 12345678, "refresh_token_expires_in": 604799}"#)
             .expect(1)
             .create();
 
@@ -671,6 +745,8 @@ mod tests {
                     assert_eq!("accesstoken", tok.access_token);
                     assert_eq!("refreshtoken", tok.refresh_token);
                     assert_eq!("Bearer", tok.token_type);
+                    assert!(tok.refresh_token_expiry_date().is_some());
+                    assert!(!tok.refresh_token_expired());
                     Ok(())
                 });
             rt.block_on(fut).expect("block on");
@@ -678,13 +754,15 @@ mod tests {
         }
         // Successful path with HTTP redirect.
         {
-            let mut inf =
-                InstalledFlow::new(app_secret, InstalledFlowReturnMethod::HTTPRedirect(8081))
-                    .delegate(FD(
-                        "authorizationcodefromlocalserver".to_string(),
-                        client.clone(),
-                    ))
-                    .build_token_getter(client.clone());
+            let mut inf = InstalledFlow::new(
+                app_secret.clone(),
+                InstalledFlowReturnMethod::HTTPRedirect(8081),
+            )
+            .delegate(FD(
+                "authorizationcodefromlocalserver".to_string(),
+                client.clone(),
+            ))
+            .build_token_getter(client.clone());
             let _m = mock("POST", "/token")
             .match_body(mockito::Matcher::Regex(".*code=authorizationcodefromlocalserver.*client_id=9022167.*".to_string()))
             .with_body(r#"{"access_token": "accesstoken", "refresh_token": "refreshtoken", "token_type": "Bearer", "expires_in": 12345678}"#)
@@ -723,6 +801,66 @@ mod tests {
             rt.block_on(fut).expect("block on");
             _m.assert();
         }
+        // ID token bound to a nonce.
+        {
+            let id_token = |nonce: &str| {
+                let claims = format!(
+                    r#"{{"iss": "https://accounts.google.com", "sub": "1234", "aud": "client", "exp": 1500, "iat": 900, "email": "user@example.com", "nonce": "{}"}}"#,
+                    nonce
+                );
+                format!(
+                    "e30.{}.c2ln",
+                    base64::encode_config(&claims, base64::URL_SAFE_NO_PAD)
+                )
+            };
+            let mut inf =
+                InstalledFlow::new(app_secret.clone(), InstalledFlowReturnMethod::Interactive)
+                    .delegate(FD("authorizationcode".to_string(), client.clone()))
+                    .nonce("n-0S6")
+                    .build_token_getter(client.clone());
+
+            let _m = mock("POST", "/token")
+                .with_body(format!(
+                    r#"{{"access_token": "accesstoken", "refresh_token": "refreshtoken", "token_type": "Bearer", "expires_in": 12345678, "id_token": "{}"}}"#,
+                    id_token("n-0S6")
+                ))
+                .expect(1)
+                .create();
+            let tok = rt
+                .block_on(inf.token(vec!["openid", "email"]))
+                .expect("block on");
+            let claims = tok.id_token_claims().unwrap().unwrap();
+            assert_eq!("1234", claims.sub);
+            assert_eq!(Some("user@example.com"), claims.email.as_deref());
+            _m.assert();
+
+            let _m = mock("POST", "/token")
+                .with_body(format!(
+                    r#"{{"access_token": "accesstoken", "refresh_token": "refreshtoken", "token_type": "Bearer", "expires_in": 12345678, "id_token": "{}"}}"#,
+                    id_token("replayed")
+                ))
+                .expect(1)
+                .create();
+            match rt.block_on(inf.token(vec!["openid", "email"])) {
+                Err(RequestError::BadServerResponse(_)) => {}
+                r => panic!("unexpected result {:?}", r),
+            }
+            _m.assert();
+
+            // A missing ID token is rejected as well.
+            drop(_m);
+            let _m = mock("POST", "/token")
+                .with_body(r#"{"access_token": "accesstoken", "refresh_token": "refreshtoken", "token_type": "Bearer", "expires_in": 12345678}"#)
+                .expect(2)
+                .create();
+            match rt.block_on(inf.token(vec!["openid", "email"])) {
+                Err(RequestError::BadServerResponse(_)) => {}
+                r => panic!("unexpected result {:?}", r),
+            }
+            // Unless no ID token was requested.
+            assert!(rt.block_on(inf.token(vec!["email"])).is_ok());
+            _m.assert();
+        }
         rt.shutdown_on_idle().wait().expect("shutdown");
     }
 
@@ -738,9 +876,26 @@ mod tests {
                 "812741506391-h38jh0j4fv0ce1krdkiq0hfvt6n5am\
                  rf.apps.googleusercontent.com",
                 vec![&"email".to_string(), &"profile".to_string()],
+                None,
                 None
             )
         );
+        assert!(build_authentication_request_url(
+            "https://accounts.google.com/o/oauth2/auth",
+            "client",
+            vec![&"openid".to_string()],
+            None,
+            Some("n-0S6")
+        )
+        .ends_with("&client_id=client&nonce=n-0S6"));
+        assert!(build_authentication_request_url(
+            "https://accounts.google.com/o/oauth2/auth",
+            "client",
+            vec![&"openid".to_string()],
+            None,
+            Some("a&b=c+d")
+        )
+        .ends_with("&client_id=client&nonce=a%26b%3Dc%2Bd"));
     }
 
     #[test]
@@ -86,12 +86,20 @@ extern crate serde_derive;
 mod authenticator;
 mod authenticator_delegate;
 mod device;
+mod discovery;
+mod encryption;
+mod external_account;
 mod helper;
+mod id_token;
+mod impersonation;
 mod installed;
+mod introspection;
 mod refresh;
 mod service_account;
 mod storage;
+mod token_exchange;
 mod types;
+mod verifier;
 
 pub use crate::authenticator::{AuthFlow, Authenticator};
 pub use crate::authenticator_delegate::{
@@ -99,11 +107,29 @@ pub use crate::authenticator_delegate::{
     PollInformation,
 };
 pub use crate::device::{DeviceFlow, GOOGLE_DEVICE_CODE_URL};
+pub use crate::discovery::{Discovery, ProviderMetadata};
+pub use crate::encryption::{EnvKey, KeyFile, KeyProvider, PassphraseKey, RawKey, KEY_LEN};
+pub use crate::external_account::{
+    CredentialSource, ExecutableSource, ExternalAccountAccess, ExternalAccountKey,
+    ImpersonationOptions, SubjectTokenFormat,
+};
 pub use crate::helper::*;
+pub use crate::id_token::IdTokenClaims;
+pub use crate::impersonation::{ImpersonatedServiceAccount, GOOGLE_IAM_CREDENTIALS_URL};
 pub use crate::installed::{InstalledFlow, InstalledFlowReturnMethod};
+pub use crate::introspection::{
+    GrantedScopesCheck, TokenInfo, TokenIntrospection, TokenIntrospectionImpl, GOOGLE_TOKENINFO_URL,
+};
 pub use crate::service_account::*;
-pub use crate::storage::{DiskTokenStorage, MemoryStorage, NullStorage, TokenStorage};
+pub use crate::storage::{
+    AsyncTokenStorage, CacheStats, DiskTokenStorage, MemoryStorage, NullStorage, ScopeMatch,
+    StorageFuture, TokenFileError, TokenFileMetadata, TokenStorage, TOKEN_FILE_VERSION,
+};
+pub use crate::token_exchange::{
+    ExchangedToken, TokenExchange, TokenExchangeFlow, ACCESS_TOKEN_TYPE,
+};
 pub use crate::types::{
-    ApplicationSecret, ConsoleApplicationSecret, FlowType, GetToken, PollError, RefreshResult,
-    RequestError, Scheme, Token, TokenType,
+    ApplicationSecret, ConsoleApplicationSecret, ErrorKind, FlowType, GetToken, OAuthError,
+    OAuthErrorCode, PollError, RequestError, Scheme, Token, TokenType,
 };
+pub use crate::verifier::{IdTokenError, IdTokenVerifier, IdTokenVerifierImpl, GOOGLE_JWKS_URL};
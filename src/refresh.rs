@@ -1,4 +1,4 @@
-use crate::types::{ApplicationSecret, JsonError, RefreshResult, RequestError};
+use crate::types::{extra_parameters, ApplicationSecret, JsonError, RequestError};
 
 use super::Token;
 use chrono::Utc;
@@ -7,6 +7,7 @@ use futures::Future;
 use hyper;
 use hyper::header;
 use serde_json as json;
+use std::collections::HashMap;
 use url::form_urlencoded;
 
 /// Implements the [OAuth2 Refresh Token Flow](https://developers.google.com/youtube/v3/guides/authentication#devices).
@@ -18,8 +19,8 @@ pub struct RefreshFlow;
 
 impl RefreshFlow {
     /// Attempt to refresh the given token, and obtain a new, valid one.
-    /// If the `RefreshResult` is `RefreshResult::Error`, you may retry within an interval
-    /// of your choice. If it is `RefreshResult:RefreshError`, your refresh token is invalid
+    /// If the error is retryable (see `RequestError::is_retryable()`), you may retry within an
+    /// interval of your choice. If it is `RequestError::Refresh`, your refresh token is invalid
     /// or your authorization was revoked. Therefore no further attempt shall be made,
     /// and you will have to re-authorize using the `DeviceFlow`
     ///
@@ -35,7 +36,7 @@ impl RefreshFlow {
         client: hyper::Client<C>,
         client_secret: ApplicationSecret,
         refresh_token: String,
-    ) -> impl 'a + Future<Item = RefreshResult, Error = RequestError> {
+    ) -> impl 'a + Future<Item = Token, Error = RequestError> {
         let req = form_urlencoded::Serializer::new(String::new())
             .extend_pairs(&[
                 ("client_id", client_secret.client_id.clone()),
@@ -52,51 +53,50 @@ impl RefreshFlow {
 
         client
             .request(request)
-            .then(|r| {
-                match r {
-                    Err(err) => return Err(RefreshResult::Error(err)),
-                    Ok(res) => {
-                        Ok(res
-                            .into_body()
-                            .concat2()
-                            .wait()
-                            .map(|c| String::from_utf8(c.into_bytes().to_vec()).unwrap())
-                            .unwrap()) // TODO: error handling
-                    }
-                }
+            .and_then(|res| {
+                let status = res.status();
+                res.into_body().concat2().map(move |body| (status, body))
             })
-            .then(move |maybe_json_str: Result<String, RefreshResult>| {
-                if let Err(e) = maybe_json_str {
-                    return Ok(e);
-                }
-                let json_str = maybe_json_str.unwrap();
+            .map_err(RequestError::ClientError)
+            .and_then(move |(status, body)| {
+                let json_str = String::from_utf8_lossy(&body);
                 #[derive(Deserialize)]
                 struct JsonToken {
                     access_token: String,
+                    refresh_token: Option<String>,
                     token_type: String,
                     expires_in: i64,
+                    refresh_token_expires_in: Option<i64>,
+                    id_token: Option<String>,
+                    scope: Option<String>,
+                    #[serde(flatten)]
+                    extra: HashMap<String, json::Value>,
                 }
 
-                match json::from_str::<JsonError>(&json_str) {
-                    Err(_) => {}
-                    Ok(res) => {
-                        return Ok(RefreshResult::RefreshError(
-                            res.error,
-                            res.error_description,
-                        ))
-                    }
+                if let Ok(res) = json::from_str::<JsonError>(&json_str) {
+                    return Err(RequestError::Refresh(res.into()));
+                }
+                if !status.is_success() {
+                    return Err(RequestError::HttpStatus(status, json_str.into_owned()));
                 }
 
-                let t: JsonToken = json::from_str(&json_str).unwrap();
-                Ok(RefreshResult::Success(Token {
+                let t: JsonToken = json::from_str(&json_str).map_err(RequestError::JSONError)?;
+                Ok(Token {
                     access_token: t.access_token,
                     token_type: t.token_type,
-                    refresh_token: refresh_token.to_string(),
+                    // The server may issue a new refresh token, invalidating the old one.
+                    refresh_token: t.refresh_token.unwrap_or(refresh_token),
                     expires_in: None,
-                    expires_in_timestamp: Some(Utc::now().timestamp() + t.expires_in),
-                }))
+                    id_token: t.id_token,
+                    scope: t.scope,
+                    extra: extra_parameters(t.extra),
+                    refresh_token_expires_in: None,
+                    refresh_token_expires_in_timestamp: t
+                        .refresh_token_expires_in
+                        .map(|e| Utc::now().timestamp().saturating_add(e)),
+                    expires_in_timestamp: Some(Utc::now().timestamp().saturating_add(t.expires_in)),
+                })
             })
-            .map_err(RequestError::Refresh)
     }
 }
 
@@ -136,7 +136,7 @@ mod tests {
                 .match_body(
                     mockito::Matcher::Regex(".*client_id=902216714886-k2v9uei3p1dk6h686jbsn9mo96tnbvto.apps.googleusercontent.com.*refresh_token=my-refresh-token.*".to_string()))
                 .with_status(200)
-                .with_body(r#"{"access_token": "new-access-token", "token_type": "Bearer", "expires_in": 1234567}"#)
+                .with_body(r#"{"access_token": "new-access-token", "token_type": "Bearer", "expires_in": 1234567, "ext_expires_in": 1234567, "expires_in_timestamp": 1}"#)
                 .create();
             let fut = RefreshFlow::refresh_token(
                 client.clone(),
@@ -144,13 +144,15 @@ mod tests {
                 refresh_token.clone(),
             )
             .then(|rr| {
-                let rr = rr.unwrap();
                 match rr {
-                    RefreshResult::Success(tok) => {
+                    Ok(tok) => {
                         assert_eq!("new-access-token", tok.access_token);
                         assert_eq!("Bearer", tok.token_type);
+                        assert_eq!("my-refresh-token", tok.refresh_token);
+                        assert_eq!(Some(&json::json!(1234567)), tok.extra.get("ext_expires_in"));
+                        assert!(!tok.extra.contains_key("expires_in_timestamp"));
                     }
-                    _ => panic!(format!("unexpected RefreshResult {:?}", rr)),
+                    _ => panic!("unexpected result {:?}", rr),
                 }
                 Ok(()) as Result<(), ()>
             });
@@ -158,6 +160,46 @@ mod tests {
             rt.block_on(fut).expect("block_on");
             _m.assert();
         }
+        // A rotated refresh token replaces the old one.
+        {
+            let _m = mockito::mock("POST", "/token")
+                .match_body(mockito::Matcher::Regex(
+                    ".*refresh_token=my-refresh-token.*".to_string(),
+                ))
+                .with_status(200)
+                .with_body(r#"{"access_token": "new-access-token", "refresh_token": "rotated-refresh-token", "token_type": "Bearer", "expires_in": 3600}"#)
+                .create();
+            let tok = rt
+                .block_on(RefreshFlow::refresh_token(
+                    client.clone(),
+                    app_secret.clone(),
+                    refresh_token.clone(),
+                ))
+                .unwrap();
+            assert_eq!("rotated-refresh-token", tok.refresh_token);
+            assert!(tok.extra.is_empty());
+            let stored: Token = json::from_str(&json::to_string(&tok).unwrap()).unwrap();
+            assert_eq!(tok, stored);
+            _m.assert();
+        }
+        // An error page instead of a token response.
+        {
+            let _m = mockito::mock("POST", "/token")
+                .with_status(503)
+                .with_body("<html><body>Service Unavailable</body></html>")
+                .create();
+            match rt.block_on(RefreshFlow::refresh_token(
+                client.clone(),
+                app_secret.clone(),
+                refresh_token.clone(),
+            )) {
+                Err(e @ RequestError::HttpStatus(hyper::StatusCode::SERVICE_UNAVAILABLE, _)) => {
+                    assert!(e.is_retryable())
+                }
+                r => panic!("unexpected result {:?}", r),
+            }
+            _m.assert();
+        }
         // Refresh error.
         {
             let _m = mockito::mock("POST", "/token")
@@ -168,12 +210,12 @@ mod tests {
                 .create();
 
             let fut = RefreshFlow::refresh_token(client, app_secret, refresh_token).then(|rr| {
-                let rr = rr.unwrap();
                 match rr {
-                    RefreshResult::RefreshError(e, None) => {
-                        assert_eq!(e, "invalid_token");
+                    Err(RequestError::Refresh(ref e)) => {
+                        assert_eq!(e.code.as_str(), "invalid_token");
+                        assert_eq!(e.description, None);
                     }
-                    _ => panic!(format!("unexpected RefreshResult {:?}", rr)),
+                    _ => panic!("unexpected result {:?}", rr),
                 }
                 Ok(())
             });
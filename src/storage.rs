@@ -3,15 +3,24 @@
 // See project root for licensing information.
 //
 
-use std::collections::hash_map::DefaultHasher;
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::error::Error;
 use std::fmt;
 use std::fs;
 use std::hash::{Hash, Hasher};
 use std::io;
 use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::types::Token;
+use crate::encryption::{KeyProvider, Sealer};
+use crate::types::{ErrorKind, Token};
+use ::log::{log, warn};
+use chrono::Utc;
+use fs2::FileExt;
+use futures::{future, Future};
 use itertools::Itertools;
 
 /// Implements a specialized storage to set and retrieve `Token` instances.
@@ -32,9 +41,86 @@ pub trait TokenStorage {
     ) -> Result<(), Self::Error>;
     /// A `None` result indicates that there is no token for the given scope_hash.
     fn get(&self, scope_hash: u64, scopes: &Vec<&str>) -> Result<Option<Token>, Self::Error>;
+
+    /// The kind of `error`, reported by `RequestError::kind()`. By default, errors are
+    /// considered temporary failures of the storage.
+    fn error_kind(error: &Self::Error) -> ErrorKind {
+        let _ = error;
+        ErrorKind::Storage
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// The future returned by `AsyncTokenStorage` operations.
+pub type StorageFuture<T, E> = Box<dyn Future<Item = T, Error = E> + Send>;
+
+/// A `TokenStorage` whose operations complete asynchronously, e.g. because tokens are kept in a
+/// remote database or secret store shared by many processes.
+///
+/// The storage is locked only while an operation is started; the returned future must not
+/// borrow from it. Every `TokenStorage` is also an `AsyncTokenStorage` whose operations complete
+/// immediately.
+pub trait AsyncTokenStorage {
+    type Error: 'static + Error + Send + Sync;
+
+    /// If `token` is None, it is invalid or revoked and should be removed from storage.
+    /// Otherwise, it should be saved.
+    fn store(
+        &mut self,
+        scope_hash: u64,
+        scopes: Vec<String>,
+        token: Option<Token>,
+    ) -> StorageFuture<(), Self::Error>;
+    /// A `None` result indicates that there is no token for the given scope_hash.
+    fn load(
+        &mut self,
+        scope_hash: u64,
+        scopes: Vec<String>,
+    ) -> StorageFuture<Option<Token>, Self::Error>;
+
+    /// The kind of `error`, reported by `RequestError::kind()`. By default, errors are
+    /// considered temporary failures of the storage.
+    fn error_kind(error: &Self::Error) -> ErrorKind {
+        let _ = error;
+        ErrorKind::Storage
+    }
+}
+
+impl<S: TokenStorage> AsyncTokenStorage for S {
+    type Error = S::Error;
+
+    fn store(
+        &mut self,
+        scope_hash: u64,
+        scopes: Vec<String>,
+        token: Option<Token>,
+    ) -> StorageFuture<(), Self::Error> {
+        let scopes = scopes.iter().map(String::as_str).collect();
+        Box::new(future::result(self.set(scope_hash, &scopes, token)))
+    }
+
+    fn load(
+        &mut self,
+        scope_hash: u64,
+        scopes: Vec<String>,
+    ) -> StorageFuture<Option<Token>, Self::Error> {
+        let scopes = scopes.iter().map(String::as_str).collect();
+        Box::new(future::result(self.get(scope_hash, &scopes)))
+    }
+
+    fn error_kind(error: &Self::Error) -> ErrorKind {
+        S::error_kind(error)
+    }
 }
 
 /// Calculate a hash value describing the scopes, and return a sorted Vec of the scopes.
+///
+/// The scopes are sorted and deduplicated, joined by single spaces (the same form they take in
+/// an OAuth `scope` parameter), and the resulting string is hashed with 64-bit FNV-1a. Unlike
+/// `std`'s `DefaultHasher`, this value is stable across Rust releases, which matters because it
+/// is persisted by `DiskTokenStorage`.
 pub fn hash_scopes<I, T>(scopes: I) -> (u64, Vec<String>)
 where
     T: Into<String>,
@@ -42,9 +128,22 @@ where
 {
     let mut sv: Vec<String> = scopes.into_iter().map(Into::into).collect();
     sv.sort();
-    let mut sh = DefaultHasher::new();
-    sv.hash(&mut sh);
-    (sh.finish(), sv)
+    sv.dedup();
+    let hash = sv.join(" ").bytes().fold(FNV_OFFSET_BASIS, |h, b| {
+        (h ^ u64::from(b)).wrapping_mul(FNV_PRIME)
+    });
+    (hash, sv)
+}
+
+/// Hash a cache key made of more than scopes, e.g. of a subject and the hash of its scopes from
+/// `hash_scopes()`. The value is not stable across Rust releases, so it must not be persisted.
+///
+/// A `MemoryStorage` holding tokens under such keys must use `ScopeMatch::Exact`; otherwise
+/// tokens stored under other keys are found by their scopes.
+pub(crate) fn hash_key<K: Hash>(key: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
 }
 
 /// A storage that remembers nothing.
@@ -76,16 +175,176 @@ impl TokenStorage for NullStorage {
     }
 }
 
+/// Decides which stored token is returned when looking up a set of scopes.
+///
+/// A stored token is a candidate if it was stored for exactly the requested scopes, or, unless
+/// the policy is `Exact`, for a superset of them.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ScopeMatch {
+    /// Only return a token stored for exactly the requested scopes.
+    Exact,
+    /// Return the candidate stored for the fewest scopes; an exact match is always preferred.
+    /// Among candidates with equally many scopes, the one expiring last wins. This is the
+    /// default.
+    #[default]
+    SmallestSuperset,
+    /// Return the candidate expiring last. Among candidates expiring at the same time, the one
+    /// stored for fewer scopes wins.
+    FreshestSuperset,
+}
+
+/// Counters describing how a `MemoryStorage` has been used. Cloned values share their counters,
+/// so a clone can be kept to observe a storage that was moved elsewhere.
+#[derive(Clone, Debug, Default)]
+pub struct CacheStats(Arc<CacheCounters>);
+
+#[derive(Debug, Default)]
+struct CacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    expirations: AtomicU64,
+    entries: AtomicUsize,
+}
+
+impl CacheStats {
+    /// Number of lookups that found a token.
+    pub fn hits(&self) -> u64 {
+        self.0.hits.load(Ordering::Relaxed)
+    }
+
+    /// Number of lookups that found no token.
+    pub fn misses(&self) -> u64 {
+        self.0.misses.load(Ordering::Relaxed)
+    }
+
+    /// Number of tokens removed because the maximum number of entries was reached.
+    pub fn evictions(&self) -> u64 {
+        self.0.evictions.load(Ordering::Relaxed)
+    }
+
+    /// Number of expired tokens without refresh token that were removed.
+    pub fn expirations(&self) -> u64 {
+        self.0.expirations.load(Ordering::Relaxed)
+    }
+
+    /// Number of tokens currently stored.
+    pub fn entries(&self) -> usize {
+        self.0.entries.load(Ordering::Relaxed)
+    }
+}
+
+/// Tracks when stored tokens were last used, to find the least recently used one.
+#[derive(Debug, Default)]
+struct Recency {
+    tick: u64,
+    last_used: HashMap<u64, u64>,
+    /// `last_used`, inverted.
+    by_tick: BTreeMap<u64, u64>,
+}
+
+impl Recency {
+    fn touch(&mut self, hash: u64) {
+        self.remove(hash);
+        self.tick += 1;
+        self.last_used.insert(hash, self.tick);
+        self.by_tick.insert(self.tick, hash);
+    }
+
+    fn remove(&mut self, hash: u64) {
+        if let Some(tick) = self.last_used.remove(&hash) {
+            self.by_tick.remove(&tick);
+        }
+    }
+
+    fn least_recently_used(&self) -> Option<u64> {
+        self.by_tick.values().next().cloned()
+    }
+}
+
 /// A storage that remembers values for one session only.
+///
+/// By default the storage grows without limit. With `max_entries()`, the least recently used
+/// tokens are evicted once the limit is exceeded. Tokens that have expired and can't be refreshed
+/// (i.e. have no refresh token) are always removed when a token is stored.
 #[derive(Debug, Default)]
 pub struct MemoryStorage {
-    tokens: Vec<JSONToken>,
+    tokens: TokenIndex,
+    scope_match: ScopeMatch,
+    max_entries: Option<usize>,
+    recency: Mutex<Recency>,
+    /// Tokens without refresh token, by expiry.
+    expiring: BTreeSet<(i64, u64)>,
+    stats: CacheStats,
 }
 
 impl MemoryStorage {
     pub fn new() -> MemoryStorage {
         Default::default()
     }
+
+    /// Use the provided policy to choose among stored tokens.
+    pub fn scope_match(self, scope_match: ScopeMatch) -> Self {
+        MemoryStorage {
+            scope_match,
+            ..self
+        }
+    }
+
+    /// Store at most `max_entries` tokens, evicting the least recently used ones.
+    pub fn max_entries(self, max_entries: usize) -> Self {
+        MemoryStorage {
+            max_entries: Some(max_entries),
+            ..self
+        }
+    }
+
+    /// Record usage in the provided `CacheStats` instead of a new one.
+    pub fn stats_into(self, stats: CacheStats) -> Self {
+        stats
+            .0
+            .entries
+            .store(self.tokens.tokens.len(), Ordering::Relaxed);
+        MemoryStorage { stats, ..self }
+    }
+
+    /// Usage statistics of this storage.
+    pub fn stats(&self) -> CacheStats {
+        self.stats.clone()
+    }
+
+    fn remove(&mut self, hash: u64) {
+        if let Some(t) = self.tokens.remove(hash) {
+            if let Some(expiry) = t.token.expires_in_timestamp {
+                self.expiring.remove(&(expiry, hash));
+            }
+            self.recency.lock().unwrap().remove(hash);
+        }
+    }
+
+    /// Remove expired tokens without refresh token, then the least recently used tokens while
+    /// there are too many.
+    fn purge(&mut self) {
+        // Same margin as `Token::expired()`.
+        let now = Utc::now().timestamp() + 60;
+        while let Some(&(expiry, hash)) = self.expiring.iter().next() {
+            if expiry > now {
+                break;
+            }
+            self.remove(hash);
+            self.stats.0.expirations.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(max_entries) = self.max_entries {
+            while self.tokens.tokens.len() > max_entries {
+                let lru = self.recency.lock().unwrap().least_recently_used();
+                match lru {
+                    Some(hash) => self.remove(hash),
+                    None => break,
+                }
+                self.stats.0.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
 }
 
 impl TokenStorage for MemoryStorage {
@@ -97,42 +356,133 @@ impl TokenStorage for MemoryStorage {
         scopes: &Vec<&str>,
         token: Option<Token>,
     ) -> Result<(), NullError> {
-        let matched = self.tokens.iter().find_position(|x| x.hash == scope_hash);
-        if let Some((idx, _)) = matched {
-            self.tokens.remove(idx);
+        self.remove(scope_hash);
+        if let Some(t) = token {
+            if let (true, Some(expiry)) = (t.refresh_token.is_empty(), t.expires_in_timestamp) {
+                self.expiring.insert((expiry, scope_hash));
+            }
+            self.recency.lock().unwrap().touch(scope_hash);
+            self.tokens.insert(JSONToken::new(scope_hash, scopes, t));
         }
+        self.purge();
+        self.stats
+            .0
+            .entries
+            .store(self.tokens.tokens.len(), Ordering::Relaxed);
+        Ok(())
+    }
 
-        match token {
+    fn get(&self, scope_hash: u64, scopes: &Vec<&str>) -> Result<Option<Token>, NullError> {
+        match self.tokens.find(scope_hash, scopes, self.scope_match) {
             Some(t) => {
-                self.tokens.push(JSONToken {
-                    hash: scope_hash,
-                    scopes: Some(scopes.iter().map(|x| x.to_string()).collect()),
-                    token: t.clone(),
-                });
-                ()
+                self.stats.0.hits.fetch_add(1, Ordering::Relaxed);
+                self.recency.lock().unwrap().touch(t.hash);
+                Ok(Some(t.token.clone()))
             }
-            None => {}
-        };
-        Ok(())
+            None => {
+                self.stats.0.misses.fetch_add(1, Ordering::Relaxed);
+                Ok(None)
+            }
+        }
     }
+}
 
-    fn get(&self, scope_hash: u64, scopes: &Vec<&str>) -> Result<Option<Token>, NullError> {
-        let scopes: Vec<_> = scopes.iter().sorted().unique().collect();
-
-        for t in &self.tokens {
-            if let Some(token_scopes) = &t.scopes {
-                let matched = token_scopes
-                    .iter()
-                    .filter(|x| scopes.contains(&&&x[..]))
-                    .count();
-                if matched >= scopes.len() {
-                    return Result::Ok(Some(t.token.clone()));
+/// Stored tokens, indexed by scope hash and by each individual scope, so that lookups don't
+/// need to scan all tokens.
+#[derive(Debug, Default)]
+struct TokenIndex {
+    tokens: HashMap<u64, JSONToken>,
+    /// For every scope, the hashes of the tokens stored for it.
+    by_scope: HashMap<String, HashSet<u64>>,
+}
+
+impl TokenIndex {
+    /// Add `token`, replacing any token stored under the same hash.
+    fn insert(&mut self, token: JSONToken) {
+        self.remove(token.hash);
+        for scope in token.matched_scopes() {
+            self.by_scope
+                .entry(scope.to_string())
+                .or_default()
+                .insert(token.hash);
+        }
+        self.tokens.insert(token.hash, token);
+    }
+
+    fn remove(&mut self, hash: u64) -> Option<JSONToken> {
+        let token = self.tokens.remove(&hash)?;
+        for scope in token.matched_scopes() {
+            if let Entry::Occupied(mut hashes) = self.by_scope.entry(scope.to_string()) {
+                hashes.get_mut().remove(&hash);
+                if hashes.get().is_empty() {
+                    hashes.remove();
                 }
-            } else if scope_hash == t.hash {
-                return Result::Ok(Some(t.token.clone()));
             }
         }
-        Result::Ok(None)
+        Some(token)
+    }
+
+    fn clear(&mut self) {
+        self.tokens.clear();
+        self.by_scope.clear();
+    }
+
+    /// All tokens, in a stable order.
+    fn to_vec(&self) -> Vec<JSONToken> {
+        self.tokens
+            .values()
+            .sorted_by_key(|t| t.hash)
+            .cloned()
+            .collect()
+    }
+
+    /// Find the token to use for `scopes`, whose hash is `scope_hash`.
+    fn find(&self, scope_hash: u64, scopes: &[&str], policy: ScopeMatch) -> Option<&JSONToken> {
+        let exact = self.tokens.get(&scope_hash);
+        if policy == ScopeMatch::Exact {
+            return exact;
+        }
+
+        // Only tokens stored for the least common of the requested scopes need to be looked at.
+        let mut postings = Vec::with_capacity(scopes.len());
+        for scope in scopes {
+            match self.by_scope.get(*scope) {
+                Some(hashes) => postings.push(hashes),
+                None => return exact,
+            }
+        }
+        let candidates: Box<dyn Iterator<Item = &u64>> =
+            match postings.iter().min_by_key(|hashes| hashes.len()) {
+                Some(hashes) => Box::new(hashes.iter()),
+                None => Box::new(self.tokens.keys()),
+            };
+        let supersets = candidates
+            .filter(|hash| postings.iter().all(|hashes| hashes.contains(hash)))
+            .filter_map(|hash| self.tokens.get(hash))
+            .chain(exact);
+
+        // Tokens without expiry never expire. The hash makes the choice deterministic.
+        let expiry = |t: &JSONToken| t.token.expires_in_timestamp.unwrap_or(i64::MAX);
+        let num_scopes = |t: &JSONToken| t.matched_scopes().len();
+        match policy {
+            ScopeMatch::Exact => unreachable!(),
+            ScopeMatch::SmallestSuperset => supersets.min_by_key(|t| {
+                (
+                    t.hash != scope_hash,
+                    num_scopes(t),
+                    std::cmp::Reverse(expiry(t)),
+                    t.hash,
+                )
+            }),
+            ScopeMatch::FreshestSuperset => supersets.min_by_key(|t| {
+                (
+                    std::cmp::Reverse(expiry(t)),
+                    t.hash != scope_hash,
+                    num_scopes(t),
+                    t.hash,
+                )
+            }),
+        }
     }
 }
 
@@ -140,132 +490,1033 @@ impl TokenStorage for MemoryStorage {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct JSONToken {
     pub hash: u64,
+    /// The requested scopes.
     pub scopes: Option<Vec<String>>,
     pub token: Token,
+    /// The scopes granted by the server, if it said so.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub granted_scopes: Option<Vec<String>>,
+}
+
+impl JSONToken {
+    fn new(hash: u64, scopes: &[&str], token: Token) -> JSONToken {
+        let granted_scopes = token
+            .granted_scopes()
+            .map(|granted| granted.into_iter().map(str::to_string).collect());
+        JSONToken {
+            hash,
+            scopes: Some(scopes.iter().map(|x| x.to_string()).collect()),
+            token,
+            granted_scopes,
+        }
+    }
+
+    /// The scopes this token is found by: the granted scopes if they are known, otherwise
+    /// the requested ones.
+    fn matched_scopes(&self) -> Vec<&str> {
+        self.granted_scopes
+            .as_ref()
+            .or(self.scopes.as_ref())
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .collect()
+    }
 }
 
-/// List of tokens in a JSON object
+/// The version of the token file format written by `DiskTokenStorage`.
+///
+/// * 1: `{"tokens": [...]}`, without version field.
+/// * 2: adds `version` and `metadata`; scope hashes are computed by the current `hash_scopes()`.
+pub const TOKEN_FILE_VERSION: u32 = 2;
+
+/// Describes what the tokens in a token file belong to. `DiskTokenStorage` keeps this
+/// information, but doesn't interpret it.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenFileMetadata {
+    /// The account (e.g. user or service account email) the tokens were issued to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// The token endpoint the tokens were obtained from and can be refreshed at.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_uri: Option<String>,
+    /// The client ID the tokens were issued to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+}
+
+/// Contents of a token file.
 #[derive(Serialize, Deserialize)]
 struct JSONTokens {
+    /// Missing in version 1 files.
+    #[serde(default = "legacy_version")]
+    pub version: u32,
+    #[serde(default)]
+    pub metadata: TokenFileMetadata,
     pub tokens: Vec<JSONToken>,
 }
 
+fn legacy_version() -> u32 {
+    1
+}
+
+/// Used to check the version of a token file before trying to parse it.
+#[derive(Deserialize)]
+struct JSONTokensVersion {
+    #[serde(default = "legacy_version")]
+    version: u32,
+}
+
+/// Errors of `DiskTokenStorage`.
+#[derive(Debug)]
+pub enum TokenFileError {
+    /// The token file or its lock file could not be accessed.
+    Io(io::Error),
+    /// The token file's contents are not valid.
+    Corrupted(serde_json::Error),
+    /// The token file was written in a format newer than `TOKEN_FILE_VERSION`, probably by a
+    /// newer version of this crate.
+    UnsupportedVersion(u32),
+    /// The encrypted token file could not be decrypted, because the key is wrong or the file was
+    /// modified.
+    Decryption(io::Error),
+}
+
+impl TokenFileError {
+    /// The kind of this error. A token file that can't be read won't become readable by
+    /// retrying, so only failures to access it are considered temporary.
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            TokenFileError::Io(_) => ErrorKind::Storage,
+            TokenFileError::Corrupted(_)
+            | TokenFileError::UnsupportedVersion(_)
+            | TokenFileError::Decryption(_) => ErrorKind::Configuration,
+        }
+    }
+}
+
+impl fmt::Display for TokenFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            TokenFileError::Io(ref e) => write!(f, "Token file error: {}", e),
+            TokenFileError::Corrupted(ref e) => write!(f, "Token file is corrupted: {}", e),
+            TokenFileError::UnsupportedVersion(v) => write!(
+                f,
+                "Token file has version {}, but only versions up to {} are supported",
+                v, TOKEN_FILE_VERSION
+            ),
+            TokenFileError::Decryption(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for TokenFileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            TokenFileError::Io(ref e) | TokenFileError::Decryption(ref e) => Some(e),
+            TokenFileError::Corrupted(ref e) => Some(e),
+            TokenFileError::UnsupportedVersion(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for TokenFileError {
+    fn from(e: io::Error) -> TokenFileError {
+        TokenFileError::Io(e)
+    }
+}
+
+impl From<TokenFileError> for io::Error {
+    fn from(e: TokenFileError) -> io::Error {
+        match e {
+            TokenFileError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
 /// Serializes tokens to a JSON file on disk.
+///
+/// Several processes may share one token file: every modification is a read-modify-write under
+/// an exclusive advisory lock, and lookups reload the file first if another process has changed
+/// it. The lock is taken on a separate `<location>.lock` file, as the token file itself is
+/// replaced on every write.
+///
+/// Writing tokens requires that the lock file can be created, i.e. that the directory of the
+/// token file is writable. If it can't be created, tokens are still read, but without lock: as
+/// long as no other process can write the token file either, this is safe.
+///
+/// Use `new_encrypted()` to encrypt the stored tokens.
 #[derive(Default)]
 pub struct DiskTokenStorage {
     location: String,
-    tokens: Vec<JSONToken>,
+    cache: Mutex<DiskCache>,
+    sealer: Option<Sealer>,
+    scope_match: ScopeMatch,
+    /// Metadata to write instead of the one read from the file.
+    metadata: Option<TokenFileMetadata>,
+}
+
+/// The tokens last read from or written to disk.
+#[derive(Default)]
+struct DiskCache {
+    tokens: TokenIndex,
+    metadata: TokenFileMetadata,
+    /// Identifies the version of the file that `tokens` corresponds to.
+    stamp: Option<FileStamp>,
+}
+
+/// Cheap fingerprint of the token file, used to notice writes by other processes.
+#[derive(Debug, PartialEq)]
+struct FileStamp {
+    len: u64,
+    modified: Option<SystemTime>,
+    /// Every write creates a new file, so on unix the inode changes even if the size and
+    /// (coarse) modification time do not.
+    inode: u64,
+}
+
+impl FileStamp {
+    /// `None` if there is no file at `location`.
+    fn of(location: &str) -> Result<Option<FileStamp>, io::Error> {
+        let metadata = match fs::metadata(location) {
+            Ok(m) => m,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        #[cfg(unix)]
+        let inode = {
+            use std::os::unix::fs::MetadataExt;
+            metadata.ino()
+        };
+        #[cfg(not(unix))]
+        let inode = 0;
+        Ok(Some(FileStamp {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            inode,
+        }))
+    }
 }
 
 impl DiskTokenStorage {
-    pub fn new<S: AsRef<str>>(location: S) -> Result<DiskTokenStorage, io::Error> {
-        let mut dts = DiskTokenStorage {
+    /// Read the token file at `location`, if it exists. Files in an older format are upgraded
+    /// in place.
+    pub fn new<S: AsRef<str>>(location: S) -> Result<DiskTokenStorage, TokenFileError> {
+        let dts = DiskTokenStorage {
             location: location.as_ref().to_owned(),
-            tokens: Vec::new(),
+            cache: Mutex::new(DiskCache::default()),
+            sealer: None,
+            scope_match: ScopeMatch::default(),
+            metadata: None,
         };
 
-        // best-effort
-        let read_result = dts.load_from_file();
-
-        match read_result {
-            Result::Ok(()) => Result::Ok(dts),
-            Result::Err(e) => {
-                match e.kind() {
-                    io::ErrorKind::NotFound => Result::Ok(dts), // File not found; ignore and create new one
-                    _ => Result::Err(e),                        // e.g. PermissionDenied
+        match dts.lock(true) {
+            Ok(_lock) => {
+                if dts.load_from_file()? {
+                    dts.write_to_file()?;
                 }
             }
+            // The file is upgraded on the next write instead, if there ever is one.
+            Err(_) => {
+                let _lock = dts.read_lock()?;
+                dts.load_from_file()?;
+            }
+        }
+        Ok(dts)
+    }
+
+    /// Like `new()`, but the tokens are encrypted at rest with a key obtained from
+    /// `key_provider`. The file is read on first use, so that a failure to decrypt it is
+    /// reported through `AuthenticatorDelegate::token_storage_failure()`.
+    pub fn new_encrypted<S, K>(location: S, key_provider: K) -> DiskTokenStorage
+    where
+        S: AsRef<str>,
+        K: 'static + KeyProvider + Send + Sync,
+    {
+        DiskTokenStorage {
+            location: location.as_ref().to_owned(),
+            cache: Mutex::new(DiskCache::default()),
+            sealer: Some(Sealer::new(key_provider)),
+            scope_match: ScopeMatch::default(),
+            metadata: None,
+        }
+    }
+
+    /// Write the provided metadata to the token file from now on.
+    pub fn with_metadata(self, metadata: TokenFileMetadata) -> Self {
+        DiskTokenStorage {
+            metadata: Some(metadata),
+            ..self
+        }
+    }
+
+    /// The metadata stored in the token file, or the one set by `with_metadata()`.
+    pub fn metadata(&self) -> Result<TokenFileMetadata, TokenFileError> {
+        if let Some(ref metadata) = self.metadata {
+            return Ok(metadata.clone());
+        }
+        self.reload_if_changed()?;
+        Ok(self.cache.lock().unwrap().metadata.clone())
+    }
+
+    /// Use the provided policy to choose among stored tokens.
+    pub fn scope_match(self, scope_match: ScopeMatch) -> Self {
+        DiskTokenStorage {
+            scope_match,
+            ..self
+        }
+    }
+
+    /// Open the lock file belonging to the token file and lock it. The lock is released when the
+    /// returned file is dropped.
+    fn lock(&self, exclusive: bool) -> Result<fs::File, io::Error> {
+        let lock_file = open_private_file(&format!("{}.lock", self.location), false)?;
+        if exclusive {
+            lock_file.lock_exclusive()?;
+        } else {
+            lock_file.lock_shared()?;
+        }
+        Ok(lock_file)
+    }
+
+    /// Take a shared lock for reading the token file. `None` if the lock file can't be created,
+    /// e.g. in a read-only directory; see the type documentation.
+    fn read_lock(&self) -> Result<Option<fs::File>, io::Error> {
+        match open_private_file(&format!("{}.lock", self.location), false) {
+            Ok(lock_file) => {
+                lock_file.lock_shared()?;
+                Ok(Some(lock_file))
+            }
+            Err(_) => Ok(None),
         }
     }
 
-    fn load_from_file(&mut self) -> Result<(), io::Error> {
-        let mut f = fs::OpenOptions::new().read(true).open(&self.location)?;
+    /// Replace the cached tokens by the ones stored at `location`; a missing file counts as
+    /// empty. Returns `true` if the file is in an older format, i.e. should be written back.
+    /// Must be called while holding the lock.
+    fn load_from_file(&self) -> Result<bool, TokenFileError> {
+        let mut cache = self.cache.lock().unwrap();
+        cache.stamp = FileStamp::of(&self.location)?;
+        cache.tokens.clear();
+        cache.metadata = TokenFileMetadata::default();
+        let written_at = match cache.stamp {
+            Some(ref stamp) => stamp.modified,
+            None => return Ok(false),
+        };
+
+        let mut f = match fs::OpenOptions::new().read(true).open(&self.location) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        check_permissions(&self.location, &f);
         let mut contents = String::new();
 
-        match f.read_to_string(&mut contents) {
-            Result::Err(e) => return Result::Err(e),
-            Result::Ok(_sz) => (),
+        f.read_to_string(&mut contents)?;
+        if let Some(ref sealer) = self.sealer {
+            let plaintext = sealer.open(&contents).map_err(|e| match e.kind() {
+                io::ErrorKind::InvalidData => TokenFileError::Decryption(e),
+                _ => TokenFileError::Io(e),
+            })?;
+            contents = String::from_utf8(plaintext).map_err(|e| {
+                TokenFileError::Decryption(io::Error::new(io::ErrorKind::InvalidData, e))
+            })?;
         }
 
-        let tokens: JSONTokens;
+        let version = serde_json::from_str::<JSONTokensVersion>(&contents)
+            .map_err(TokenFileError::Corrupted)?
+            .version;
+        if version > TOKEN_FILE_VERSION {
+            return Err(TokenFileError::UnsupportedVersion(version));
+        }
+        let tokens: JSONTokens =
+            serde_json::from_str(&contents).map_err(TokenFileError::Corrupted)?;
 
-        match serde_json::from_str(&contents) {
-            Result::Err(e) => return Result::Err(io::Error::new(io::ErrorKind::InvalidData, e)),
-            Result::Ok(t) => tokens = t,
+        let mut migrated = tokens.version < TOKEN_FILE_VERSION;
+        cache.metadata = tokens.metadata;
+        for mut t in tokens.tokens {
+            match t.scopes {
+                Some(ref scopes) => {
+                    // Version 1 files may carry hashes that are no longer computed.
+                    let (hash, _) = hash_scopes(scopes.iter().map(String::as_str));
+                    if hash != t.hash {
+                        t.hash = hash;
+                        migrated = true;
+                    }
+                }
+                None => {
+                    // Without scopes the hash can't be recomputed, and a stale hash could collide
+                    // with a current one. The token will simply be obtained again.
+                    migrated = true;
+                    continue;
+                }
+            }
+            if let (Some(expires_in), None) = (t.token.expires_in, t.token.expires_in_timestamp) {
+                // A relative expiry is meaningless once stored; the file's modification time is
+                // the best guess of when it was relative to.
+                let written_at = written_at
+                    .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs() as i64)
+                    .unwrap_or(0);
+                t.token.expires_in_timestamp = Some(written_at + expires_in);
+                t.token.expires_in = None;
+                migrated = true;
+            }
+            // Later entries are newer; after migration they may replace older ones.
+            cache.tokens.insert(t);
         }
+        Result::Ok(migrated)
+    }
 
-        for t in tokens.tokens {
-            self.tokens.push(t);
+    /// Reload the cached tokens if the file was changed since it was last read or written.
+    fn reload_if_changed(&self) -> Result<(), TokenFileError> {
+        let stamp = FileStamp::of(&self.location)?;
+        if stamp == self.cache.lock().unwrap().stamp {
+            return Ok(());
         }
-        return Result::Ok(());
+        let _lock = self.read_lock()?;
+        self.load_from_file().map(|_| ())
     }
 
-    pub fn dump_to_file(&mut self) -> Result<(), io::Error> {
-        let mut jsontokens = JSONTokens { tokens: Vec::new() };
+    pub fn dump_to_file(&mut self) -> Result<(), TokenFileError> {
+        let _lock = self.lock(true)?;
+        self.write_to_file()
+    }
 
-        for token in self.tokens.iter() {
-            jsontokens.tokens.push((*token).clone());
+    /// Write the cached tokens to disk. Must be called while holding the exclusive lock.
+    fn write_to_file(&self) -> Result<(), TokenFileError> {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(ref metadata) = self.metadata {
+            cache.metadata = metadata.clone();
         }
+        let jsontokens = JSONTokens {
+            version: TOKEN_FILE_VERSION,
+            metadata: cache.metadata.clone(),
+            tokens: cache.tokens.to_vec(),
+        };
 
-        let serialized;;
+        let mut serialized =
+            serde_json::to_string(&jsontokens).map_err(|e| TokenFileError::Io(e.into()))?;
+        if let Some(ref sealer) = self.sealer {
+            serialized = sealer.seal(serialized.as_bytes())?;
+        }
 
-        match serde_json::to_string(&jsontokens) {
-            Result::Err(e) => return Result::Err(io::Error::new(io::ErrorKind::InvalidData, e)),
-            Result::Ok(s) => serialized = s,
+        // Write to a temporary file next to the destination and rename it into place, so that
+        // a crash mid-write never leaves a truncated token file behind.
+        let tmp_location = format!("{}.{}.tmp", self.location, std::process::id());
+        let write_result = write_private_file(&tmp_location, serialized.as_bytes())
+            .and_then(|()| fs::rename(&tmp_location, &self.location));
+        if write_result.is_err() {
+            let _ = fs::remove_file(&tmp_location);
         }
+        write_result?;
+        sync_parent_dir(&self.location);
+        cache.stamp = FileStamp::of(&self.location)?;
+        Ok(())
+    }
+}
 
-        let mut f = fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(&self.location)?;
-        f.write(serialized.as_ref()).map(|_| ())
+/// Open `path` for writing, creating it readable and writable only by the current user if it
+/// doesn't exist yet.
+fn open_private_file(path: &str, truncate: bool) -> Result<fs::File, io::Error> {
+    let mut options = fs::OpenOptions::new();
+    options.create(true).write(true).truncate(truncate);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+/// Create `path` readable and writable only by the current user, write `contents` and flush them
+/// to disk.
+fn write_private_file(path: &str, contents: &[u8]) -> Result<(), io::Error> {
+    let mut f = open_private_file(path, true)?;
+    f.write_all(contents)?;
+    f.sync_all()
+}
+
+/// Make a preceding rename durable. This is best-effort; not all platforms allow it.
+fn sync_parent_dir(path: &str) {
+    #[cfg(unix)]
+    {
+        use std::path::Path;
+        let parent = match Path::new(path).parent() {
+            Some(p) if p.as_os_str().is_empty() => Path::new("."),
+            Some(p) => p,
+            None => return,
+        };
+        if let Ok(dir) = fs::File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+    }
+}
+
+/// Warn if the token file at `f` may be read by users other than its owner. The next write
+/// replaces it with a file that is only accessible by the owner.
+fn check_permissions(location: &str, f: &fs::File) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Ok(metadata) = f.metadata() {
+            let mode = metadata.permissions().mode();
+            if mode & 0o077 != 0 {
+                warn!(
+                    "Token file {} has permissions {:o}, which allow access by other users; \
+                     it should only be accessible by its owner (0600).",
+                    location,
+                    mode & 0o777
+                );
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (location, f);
     }
 }
 
 impl TokenStorage for DiskTokenStorage {
-    type Error = io::Error;
+    type Error = TokenFileError;
     fn set(
         &mut self,
         scope_hash: u64,
         scopes: &Vec<&str>,
         token: Option<Token>,
     ) -> Result<(), Self::Error> {
-        let matched = self.tokens.iter().find_position(|x| x.hash == scope_hash);
-        if let Some((idx, _)) = matched {
-            self.tokens.remove(idx);
-        }
-
-        match token {
-            None => (),
-            Some(t) => {
-                self.tokens.push(JSONToken {
-                    hash: scope_hash,
-                    scopes: Some(scopes.iter().map(|x| x.to_string()).collect()),
-                    token: t.clone(),
-                });
-                ()
+        // Start from the current file contents, so that tokens written by other processes
+        // are not lost.
+        let _lock = self.lock(true)?;
+        self.load_from_file()?;
+        {
+            let tokens = &mut self.cache.lock().unwrap().tokens;
+            tokens.remove(scope_hash);
+            if let Some(t) = token {
+                tokens.insert(JSONToken::new(scope_hash, scopes, t));
             }
         }
-        self.dump_to_file()
+        self.write_to_file()
     }
     fn get(&self, scope_hash: u64, scopes: &Vec<&str>) -> Result<Option<Token>, Self::Error> {
-        let scopes: Vec<_> = scopes.iter().sorted().unique().collect();
-
-        for t in &self.tokens {
-            if let Some(token_scopes) = &t.scopes {
-                let matched = token_scopes
-                    .iter()
-                    .filter(|x| scopes.contains(&&&x[..]))
-                    .count();
-                // we may have some of the tokens as denormalized (many namespaces repeated)
-                if matched >= scopes.len() {
-                    return Result::Ok(Some(t.token.clone()));
+        self.reload_if_changed()?;
+        Ok(self
+            .cache
+            .lock()
+            .unwrap()
+            .tokens
+            .find(scope_hash, scopes, self.scope_match)
+            .map(|t| t.token.clone()))
+    }
+    fn error_kind(error: &TokenFileError) -> ErrorKind {
+        error.kind()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn temp_path(name: &str) -> String {
+        let mut p = std::env::temp_dir();
+        p.push(format!("yup-oauth2-{}-{}.json", name, std::process::id()));
+        p.to_str().unwrap().to_string()
+    }
+
+    fn token(access_token: &str) -> Token {
+        Token {
+            access_token: access_token.to_string(),
+            refresh_token: "refreshtoken".to_string(),
+            token_type: "Bearer".to_string(),
+            expires_in: None,
+            id_token: None,
+            scope: None,
+            extra: HashMap::new(),
+            refresh_token_expires_in: None,
+            refresh_token_expires_in_timestamp: None,
+            expires_in_timestamp: None,
+        }
+    }
+
+    #[test]
+    fn test_hash_scopes_is_stable() {
+        // These values are persisted and must never change.
+        let (hash, scopes) = hash_scopes(vec!["https://www.googleapis.com/auth/pubsub"]);
+        assert_eq!(hash, 941061354079928783);
+        assert_eq!(scopes, vec!["https://www.googleapis.com/auth/pubsub"]);
+        assert_eq!(hash_scopes(Vec::<String>::new()).0, 14695981039346656037);
+
+        let (h1, s1) = hash_scopes(vec!["b", "a", "b"]);
+        let (h2, s2) = hash_scopes(vec!["a", "b"]);
+        assert_eq!(h1, h2);
+        assert_eq!(s1, s2);
+        assert_ne!(hash_scopes(vec!["a b"]).0, hash_scopes(vec!["ab"]).0);
+    }
+
+    #[test]
+    fn test_disk_storage_migrates_old_hashes() {
+        let location = temp_path("migrate");
+        let old = JSONTokens {
+            version: 1,
+            metadata: TokenFileMetadata::default(),
+            tokens: vec![
+                JSONToken {
+                    hash: 1,
+                    scopes: Some(vec!["scope/b".to_string(), "scope/a".to_string()]),
+                    token: token("old"),
+                    granted_scopes: None,
+                },
+                JSONToken {
+                    hash: 2,
+                    scopes: None,
+                    token: token("unscoped"),
+                    granted_scopes: None,
+                },
+                JSONToken {
+                    hash: 3,
+                    scopes: Some(vec!["scope/a".to_string(), "scope/b".to_string()]),
+                    token: token("new"),
+                    granted_scopes: None,
+                },
+            ],
+        };
+        // Version 1 files have no version and metadata fields.
+        let mut old = serde_json::to_value(&old).unwrap();
+        old.as_object_mut().unwrap().remove("version");
+        old.as_object_mut().unwrap().remove("metadata");
+        fs::write(&location, old.to_string()).unwrap();
+
+        let storage = DiskTokenStorage::new(&location).unwrap();
+        let (hash, _) = hash_scopes(vec!["scope/a", "scope/b"]);
+        assert_eq!(storage.cache.lock().unwrap().tokens.tokens.len(), 1);
+        assert!(storage
+            .cache
+            .lock()
+            .unwrap()
+            .tokens
+            .tokens
+            .contains_key(&hash));
+        assert_eq!(
+            storage
+                .get(hash, &vec!["scope/a"])
+                .unwrap()
+                .unwrap()
+                .access_token,
+            "new"
+        );
+
+        // The migrated file was written back.
+        let written: JSONTokens =
+            serde_json::from_str(&fs::read_to_string(&location).unwrap()).unwrap();
+        assert_eq!(written.version, TOKEN_FILE_VERSION);
+        assert_eq!(written.tokens.len(), 1);
+        assert_eq!(written.tokens[0].hash, hash);
+        fs::remove_file(&location).unwrap();
+        let _ = fs::remove_file(format!("{}.lock", location));
+    }
+
+    #[test]
+    fn test_disk_storage_writes_private_file_atomically() {
+        let location = temp_path("atomic");
+        let _ = fs::remove_file(&location);
+        let mut storage = DiskTokenStorage::new(&location).unwrap();
+        let (hash, scopes) = hash_scopes(vec!["scope/a"]);
+        let scopes = scopes.iter().map(String::as_str).collect();
+        storage.set(hash, &scopes, Some(token("first"))).unwrap();
+        storage.set(hash, &scopes, Some(token("second"))).unwrap();
+
+        let reloaded = DiskTokenStorage::new(&location).unwrap();
+        assert_eq!(
+            reloaded.get(hash, &scopes).unwrap().unwrap().access_token,
+            "second"
+        );
+        assert!(!Path::new(&format!("{}.{}.tmp", location, std::process::id())).exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&location).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_file(&location).unwrap();
+        let _ = fs::remove_file(format!("{}.lock", location));
+    }
+
+    #[test]
+    fn test_disk_storage_shared_between_instances() {
+        let location = temp_path("shared");
+        let _ = fs::remove_file(&location);
+        let mut first = DiskTokenStorage::new(&location).unwrap();
+        let mut second = DiskTokenStorage::new(&location).unwrap();
+        let (hash_a, scopes_a) = hash_scopes(vec!["scope/a"]);
+        let scopes_a = scopes_a.iter().map(String::as_str).collect();
+        let (hash_b, scopes_b) = hash_scopes(vec!["scope/b"]);
+        let scopes_b = scopes_b.iter().map(String::as_str).collect();
+
+        first.set(hash_a, &scopes_a, Some(token("a"))).unwrap();
+        // The second instance notices the write of the first one ...
+        assert_eq!(
+            second.get(hash_a, &scopes_a).unwrap().unwrap().access_token,
+            "a"
+        );
+        // ... and writes by either instance don't clobber each other.
+        second.set(hash_b, &scopes_b, Some(token("b"))).unwrap();
+        first.set(hash_a, &scopes_a, Some(token("a2"))).unwrap();
+        for storage in &[&first, &second] {
+            assert_eq!(
+                storage
+                    .get(hash_a, &scopes_a)
+                    .unwrap()
+                    .unwrap()
+                    .access_token,
+                "a2"
+            );
+            assert_eq!(
+                storage
+                    .get(hash_b, &scopes_b)
+                    .unwrap()
+                    .unwrap()
+                    .access_token,
+                "b"
+            );
+        }
+        fs::remove_file(&location).unwrap();
+        fs::remove_file(format!("{}.lock", location)).unwrap();
+    }
+
+    #[test]
+    fn test_disk_storage_without_lock_file() {
+        let location = temp_path("unlockable");
+        let _ = fs::remove_file(&location);
+        let (hash, scopes) = hash_scopes(vec!["scope/a"]);
+        let scopes = scopes.iter().map(String::as_str).collect();
+        DiskTokenStorage::new(&location)
+            .unwrap()
+            .set(hash, &scopes, Some(token("a")))
+            .unwrap();
+
+        // Stands in for a read-only directory, in which the lock file can't be created.
+        let lock_location = format!("{}.lock", location);
+        fs::remove_file(&lock_location).unwrap();
+        fs::create_dir(&lock_location).unwrap();
+        let mut storage = DiskTokenStorage::new(&location).unwrap();
+        assert_eq!(
+            storage.get(hash, &scopes).unwrap().unwrap().access_token,
+            "a"
+        );
+        assert!(storage.set(hash, &scopes, None).is_err());
+        fs::remove_dir(&lock_location).unwrap();
+        fs::remove_file(&location).unwrap();
+    }
+
+    #[test]
+    fn test_disk_storage_keeps_extra_parameters() {
+        let location = temp_path("extra");
+        let _ = fs::remove_file(&location);
+        let (hash, scopes) = hash_scopes(vec!["chat:write"]);
+        let scopes = scopes.iter().map(String::as_str).collect();
+        let mut tok = token("a");
+        let mut extra = HashMap::new();
+        extra.insert(
+            "team".to_string(),
+            serde_json::json!({"id": "T123", "name": "Example"}),
+        );
+        // Parameters named like fields of the token are dropped.
+        extra.insert("refresh_token".to_string(), serde_json::json!("other"));
+        tok.extra = crate::types::extra_parameters(extra);
+        assert_eq!(1, tok.extra.len());
+        DiskTokenStorage::new(&location)
+            .unwrap()
+            .set(hash, &scopes, Some(tok.clone()))
+            .unwrap();
+        let stored = DiskTokenStorage::new(&location)
+            .unwrap()
+            .get(hash, &scopes)
+            .unwrap()
+            .unwrap();
+        assert_eq!(tok, stored);
+        fs::remove_file(&location).unwrap();
+        let _ = fs::remove_file(format!("{}.lock", location));
+    }
+
+    #[test]
+    fn test_encrypted_disk_storage() {
+        use crate::encryption::{RawKey, KEY_LEN};
+
+        let location = temp_path("encrypted");
+        let _ = fs::remove_file(&location);
+        let mut storage = DiskTokenStorage::new_encrypted(&location, RawKey([1; KEY_LEN]));
+        let (hash, scopes) = hash_scopes(vec!["scope/a"]);
+        let scopes = scopes.iter().map(String::as_str).collect();
+        storage.set(hash, &scopes, Some(token("secret"))).unwrap();
+
+        assert!(!fs::read_to_string(&location).unwrap().contains("secret"));
+        let reader = DiskTokenStorage::new_encrypted(&location, RawKey([1; KEY_LEN]));
+        assert_eq!(
+            reader.get(hash, &scopes).unwrap().unwrap().access_token,
+            "secret"
+        );
+        let wrong_key = DiskTokenStorage::new_encrypted(&location, RawKey([2; KEY_LEN]));
+        match wrong_key.get(hash, &scopes) {
+            Err(TokenFileError::Decryption(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        fs::remove_file(&location).unwrap();
+        fs::remove_file(format!("{}.lock", location)).unwrap();
+    }
+
+    fn store(storage: &mut MemoryStorage, scopes: &[&str], access_token: &str, expires: i64) {
+        let (hash, scopes) = hash_scopes(scopes.iter().cloned());
+        let mut t = token(access_token);
+        t.expires_in_timestamp = Some(expires);
+        storage
+            .set(hash, &scopes.iter().map(String::as_str).collect(), Some(t))
+            .unwrap();
+    }
+
+    fn lookup(storage: &MemoryStorage, scopes: &[&str]) -> Option<String> {
+        let (hash, scopes) = hash_scopes(scopes.iter().cloned());
+        storage
+            .get(hash, &scopes.iter().map(String::as_str).collect())
+            .unwrap()
+            .map(|t| t.access_token)
+    }
+
+    #[test]
+    fn test_scope_match_policies() {
+        for &reversed in &[false, true] {
+            let mut entries = vec![
+                (vec!["a", "b", "c"], "abc", 300),
+                (vec!["a", "b"], "ab", 100),
+                (vec!["a", "c"], "ac", 200),
+                (vec!["b"], "b", 400),
+            ];
+            // The result must not depend on the order in which tokens were stored.
+            if reversed {
+                entries.reverse();
+            }
+            let mut exact = MemoryStorage::new().scope_match(ScopeMatch::Exact);
+            let mut smallest = MemoryStorage::new();
+            let mut freshest = MemoryStorage::new().scope_match(ScopeMatch::FreshestSuperset);
+            for (scopes, name, expires) in &entries {
+                for storage in [&mut exact, &mut smallest, &mut freshest] {
+                    store(storage, scopes, name, *expires);
                 }
-            } else if scope_hash == t.hash {
-                return Result::Ok(Some(t.token.clone()));
             }
+
+            assert_eq!(lookup(&exact, &["a", "b"]), Some("ab".to_string()));
+            assert_eq!(lookup(&exact, &["a"]), None);
+            assert_eq!(lookup(&exact, &["b", "c"]), None);
+
+            assert_eq!(lookup(&smallest, &["b", "a"]), Some("ab".to_string()));
+            // Both "ab" and "ac" have two scopes; "ac" expires later.
+            assert_eq!(lookup(&smallest, &["a"]), Some("ac".to_string()));
+            assert_eq!(lookup(&smallest, &["b", "c"]), Some("abc".to_string()));
+            assert_eq!(lookup(&smallest, &["b"]), Some("b".to_string()));
+            assert_eq!(lookup(&smallest, &["d"]), None);
+            assert_eq!(lookup(&smallest, &["a", "d"]), None);
+
+            assert_eq!(lookup(&freshest, &["a"]), Some("abc".to_string()));
+            assert_eq!(lookup(&freshest, &["b"]), Some("b".to_string()));
+            assert_eq!(lookup(&freshest, &["a", "b"]), Some("abc".to_string()));
+            assert_eq!(lookup(&freshest, &[]), Some("b".to_string()));
+        }
+    }
+
+    #[test]
+    fn test_scope_index_replaces_and_removes() {
+        let mut storage = MemoryStorage::new();
+        store(&mut storage, &["a", "b"], "first", 100);
+        store(&mut storage, &["a", "b"], "second", 100);
+        assert_eq!(lookup(&storage, &["a"]), Some("second".to_string()));
+        assert_eq!(storage.tokens.tokens.len(), 1);
+
+        let (hash, scopes) = hash_scopes(vec!["a", "b"]);
+        storage
+            .set(hash, &scopes.iter().map(String::as_str).collect(), None)
+            .unwrap();
+        assert_eq!(lookup(&storage, &["a"]), None);
+        assert!(storage.tokens.by_scope.is_empty());
+    }
+
+    #[test]
+    fn test_granted_scopes() {
+        let location = temp_path("granted");
+        let _ = fs::remove_file(&location);
+        let mut storage = DiskTokenStorage::new(&location).unwrap();
+        let (hash, scopes) = hash_scopes(vec!["a", "b"]);
+        let scopes = scopes.iter().map(String::as_str).collect();
+        let mut t = token("narrow");
+        t.scope = Some("a c".to_string());
+        storage.set(hash, &scopes, Some(t)).unwrap();
+
+        let storage = DiskTokenStorage::new(&location).unwrap();
+        let stored = &storage.cache.lock().unwrap().tokens.to_vec()[0];
+        assert_eq!(
+            Some(vec!["a".to_string(), "c".to_string()]),
+            stored.granted_scopes
+        );
+        // Lookups match the granted scopes.
+        for (scopes, found) in &[
+            (vec!["a"], true),
+            (vec!["c"], true),
+            (vec!["b"], false),
+            (vec!["a", "c"], true),
+        ] {
+            let (hash, scopes) = hash_scopes(scopes.iter().cloned());
+            let scopes = scopes.iter().map(String::as_str).collect();
+            assert_eq!(*found, storage.get(hash, &scopes).unwrap().is_some());
         }
-        Result::Ok(None)
+        // The token is still found for exactly the requested scopes; `Authenticator` checks
+        // with its delegate whether it may be used.
+        assert!(storage.get(hash, &scopes).unwrap().is_some());
+        fs::remove_file(&location).unwrap();
+        fs::remove_file(format!("{}.lock", location)).unwrap();
+    }
+
+    #[test]
+    fn test_scope_index_many_scope_sets() {
+        let mut storage = MemoryStorage::new();
+        for i in 0..500 {
+            let unique = format!("scope/{}", i);
+            store(&mut storage, &["common", &unique], &unique, i);
+        }
+        assert_eq!(
+            lookup(&storage, &["scope/123"]),
+            Some("scope/123".to_string())
+        );
+        assert_eq!(
+            lookup(&storage, &["common", "scope/321"]),
+            Some("scope/321".to_string())
+        );
+        // Every token has two scopes; the one expiring last wins.
+        assert_eq!(lookup(&storage, &["common"]), Some("scope/499".to_string()));
+        assert_eq!(lookup(&storage, &["scope/1", "scope/2"]), None);
+    }
+
+    #[test]
+    fn test_memory_storage_evicts_least_recently_used() {
+        let far_future = Utc::now().timestamp() + 3600;
+        let mut storage = MemoryStorage::new().max_entries(2);
+        let stats = storage.stats();
+        store(&mut storage, &["a"], "a", far_future);
+        store(&mut storage, &["b"], "b", far_future);
+        // Using "a" makes "b" the least recently used token.
+        assert_eq!(lookup(&storage, &["a"]), Some("a".to_string()));
+        store(&mut storage, &["c"], "c", far_future);
+
+        assert_eq!(lookup(&storage, &["b"]), None);
+        assert_eq!(lookup(&storage, &["a"]), Some("a".to_string()));
+        assert_eq!(lookup(&storage, &["c"]), Some("c".to_string()));
+        assert_eq!(stats.evictions(), 1);
+        assert_eq!(stats.entries(), 2);
+        assert_eq!(stats.hits(), 3);
+        assert_eq!(stats.misses(), 1);
+    }
+
+    #[test]
+    fn test_memory_storage_purges_expired_access_tokens() {
+        let now = Utc::now().timestamp();
+        let mut storage = MemoryStorage::new();
+        let stats = storage.stats();
+        // Expired, but refreshable: kept.
+        store(&mut storage, &["refreshable"], "refreshable", now - 10);
+        // Expired and not refreshable: removed as soon as another token is stored.
+        let (hash, scopes) = hash_scopes(vec!["access-only"]);
+        let mut t = token("access-only");
+        t.refresh_token = String::new();
+        t.expires_in_timestamp = Some(now + 30);
+        storage
+            .set(hash, &scopes.iter().map(String::as_str).collect(), Some(t))
+            .unwrap();
+
+        assert_eq!(lookup(&storage, &["access-only"]), None);
+        assert_eq!(
+            lookup(&storage, &["refreshable"]),
+            Some("refreshable".to_string())
+        );
+        assert_eq!(stats.expirations(), 1);
+        assert_eq!(stats.entries(), 1);
+        assert!(storage.expiring.is_empty());
+    }
+
+    #[test]
+    fn test_disk_storage_upgrades_relative_expiry() {
+        let location = temp_path("relative-expiry");
+        let (hash, _) = hash_scopes(vec!["scope/a"]);
+        let old = format!(
+            r#"{{"tokens":[{{"hash":{},"scopes":["scope/a"],"token":{{"access_token":"at","refresh_token":"rt","token_type":"Bearer","expires_in":3600,"expires_in_timestamp":null}}}}]}}"#,
+            hash
+        );
+        fs::write(&location, old).unwrap();
+
+        let storage = DiskTokenStorage::new(&location).unwrap();
+        let t = storage.get(hash, &vec!["scope/a"]).unwrap().unwrap();
+        assert_eq!(t.expires_in, None);
+        let expiry = t.expires_in_timestamp.unwrap();
+        assert!((expiry - (Utc::now().timestamp() + 3600)).abs() < 60);
+        assert!(fs::read_to_string(&location)
+            .unwrap()
+            .contains(&format!("\"version\":{}", TOKEN_FILE_VERSION)));
+        fs::remove_file(&location).unwrap();
+        fs::remove_file(format!("{}.lock", location)).unwrap();
+    }
+
+    #[test]
+    fn test_disk_storage_rejects_invalid_files() {
+        let location = temp_path("invalid");
+        fs::write(&location, r#"{"version": 99, "tokens": "something new"}"#).unwrap();
+        match DiskTokenStorage::new(&location) {
+            Err(e @ TokenFileError::UnsupportedVersion(99)) => {
+                assert_eq!(
+                    ErrorKind::Configuration,
+                    <DiskTokenStorage as TokenStorage>::error_kind(&e)
+                )
+            }
+            r => panic!("unexpected result {:?}", r.map(|_| ())),
+        }
+        // The file is left alone.
+        assert!(fs::read_to_string(&location)
+            .unwrap()
+            .contains("something new"));
+
+        fs::write(&location, r#"{"tokens": [{"hash": 1}]}"#).unwrap();
+        match DiskTokenStorage::new(&location) {
+            Err(e @ TokenFileError::Corrupted(_)) => assert_eq!(ErrorKind::Configuration, e.kind()),
+            r => panic!("unexpected result {:?}", r.map(|_| ())),
+        }
+        let e = TokenFileError::Io(io::Error::new(io::ErrorKind::PermissionDenied, "locked"));
+        assert_eq!(ErrorKind::Storage, e.kind());
+        let err: io::Error = DiskTokenStorage::new(&location).err().unwrap().into();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&location).unwrap();
+        fs::remove_file(format!("{}.lock", location)).unwrap();
+    }
+
+    #[test]
+    fn test_disk_storage_metadata() {
+        let location = temp_path("metadata");
+        let _ = fs::remove_file(&location);
+        let metadata = TokenFileMetadata {
+            account: Some("user@example.com".to_string()),
+            token_uri: Some("https://oauth2.googleapis.com/token".to_string()),
+            client_id: Some("client".to_string()),
+        };
+        let mut storage = DiskTokenStorage::new(&location)
+            .unwrap()
+            .with_metadata(metadata.clone());
+        let (hash, scopes) = hash_scopes(vec!["scope/a"]);
+        let scopes = scopes.iter().map(String::as_str).collect();
+        storage.set(hash, &scopes, Some(token("a"))).unwrap();
+
+        let reader = DiskTokenStorage::new(&location).unwrap();
+        assert_eq!(reader.metadata().unwrap(), metadata);
+        fs::remove_file(&location).unwrap();
+        fs::remove_file(format!("{}.lock", location)).unwrap();
     }
 }
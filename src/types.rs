@@ -1,10 +1,13 @@
 use chrono::{DateTime, TimeZone, Utc};
 use hyper;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
 use std::io;
 use std::str::FromStr;
 
+use crate::id_token::IdTokenClaims;
+
 use futures::prelude::*;
 
 /// A marker trait for all Flows
@@ -19,17 +22,125 @@ pub struct JsonError {
     pub error_uri: Option<String>,
 }
 
-/// All possible outcomes of the refresh flow
-#[derive(Debug)]
-pub enum RefreshResult {
-    /// Indicates connection failure
-    Error(hyper::Error),
-    /// The server did not answer with a new token, providing the server message
-    RefreshError(String, Option<String>),
-    /// The refresh operation finished successfully, providing a new `Token`
-    Success(Token),
+/// An error code of an OAuth 2.0 error response, as defined in
+/// [RFC 6749, 5.2](https://tools.ietf.org/html/rfc6749#section-5.2) and
+/// [4.1.2.1](https://tools.ietf.org/html/rfc6749#section-4.1.2.1).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OAuthErrorCode {
+    InvalidRequest,
+    InvalidClient,
+    /// The authorization code or refresh token is invalid, expired or revoked.
+    InvalidGrant,
+    UnauthorizedClient,
+    UnsupportedGrantType,
+    InvalidScope,
+    /// The user or the server denied the request.
+    AccessDenied,
+    ServerError,
+    TemporarilyUnavailable,
+    /// Any other code, e.g. a provider-specific one.
+    Other(String),
+}
+
+impl OAuthErrorCode {
+    /// The code as sent by the server.
+    pub fn as_str(&self) -> &str {
+        match *self {
+            OAuthErrorCode::InvalidRequest => "invalid_request",
+            OAuthErrorCode::InvalidClient => "invalid_client",
+            OAuthErrorCode::InvalidGrant => "invalid_grant",
+            OAuthErrorCode::UnauthorizedClient => "unauthorized_client",
+            OAuthErrorCode::UnsupportedGrantType => "unsupported_grant_type",
+            OAuthErrorCode::InvalidScope => "invalid_scope",
+            OAuthErrorCode::AccessDenied => "access_denied",
+            OAuthErrorCode::ServerError => "server_error",
+            OAuthErrorCode::TemporarilyUnavailable => "temporarily_unavailable",
+            OAuthErrorCode::Other(ref code) => code,
+        }
+    }
+
+    /// The kind of errors with this code.
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            OAuthErrorCode::InvalidGrant => ErrorKind::InvalidGrant,
+            OAuthErrorCode::InvalidClient | OAuthErrorCode::UnauthorizedClient => {
+                ErrorKind::InvalidClient
+            }
+            OAuthErrorCode::AccessDenied => ErrorKind::UserDenied,
+            OAuthErrorCode::InvalidScope | OAuthErrorCode::UnsupportedGrantType => {
+                ErrorKind::Configuration
+            }
+            _ => ErrorKind::Protocol,
+        }
+    }
+}
+
+impl<'a> From<&'a str> for OAuthErrorCode {
+    fn from(code: &'a str) -> OAuthErrorCode {
+        match code {
+            "invalid_request" => OAuthErrorCode::InvalidRequest,
+            "invalid_client" => OAuthErrorCode::InvalidClient,
+            "invalid_grant" => OAuthErrorCode::InvalidGrant,
+            "unauthorized_client" => OAuthErrorCode::UnauthorizedClient,
+            "unsupported_grant_type" => OAuthErrorCode::UnsupportedGrantType,
+            "invalid_scope" => OAuthErrorCode::InvalidScope,
+            "access_denied" => OAuthErrorCode::AccessDenied,
+            "server_error" => OAuthErrorCode::ServerError,
+            "temporarily_unavailable" => OAuthErrorCode::TemporarilyUnavailable,
+            other => OAuthErrorCode::Other(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for OAuthErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        self.as_str().fmt(f)
+    }
+}
+
+/// An error response of an OAuth 2.0 server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OAuthError {
+    pub code: OAuthErrorCode,
+    /// A human-readable explanation, if the server gave one.
+    pub description: Option<String>,
+    /// A web page with information about the error, if the server gave one.
+    pub uri: Option<String>,
+}
+
+impl OAuthError {
+    /// An error with the given `code`, which is parsed into an `OAuthErrorCode`.
+    pub fn new<S: AsRef<str>>(code: S, description: Option<String>) -> OAuthError {
+        OAuthError {
+            code: code.as_ref().into(),
+            description,
+            uri: None,
+        }
+    }
+}
+
+impl From<JsonError> for OAuthError {
+    fn from(value: JsonError) -> OAuthError {
+        OAuthError {
+            code: value.error.as_str().into(),
+            description: value.error_description,
+            uri: value.error_uri,
+        }
+    }
+}
+
+impl fmt::Display for OAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        self.code.fmt(f)?;
+        if let Some(ref desc) = self.description {
+            write!(f, ": {}", desc)?;
+        }
+        Ok(())
+    }
 }
 
+impl Error for OAuthError {}
+
 /// Encapsulates all possible results of a `poll_token(...)` operation in the Device flow.
 #[derive(Debug)]
 pub enum PollError {
@@ -45,6 +156,31 @@ pub enum PollError {
     Other(String),
 }
 
+/// The kind of a `RequestError`, for deciding how to handle it without matching on the
+/// details.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// The server could not be reached, the connection failed, or the server was temporarily
+    /// unable to answer.
+    Transport,
+    /// The server responded with an error or with something unexpected.
+    Protocol,
+    /// The authorization code or refresh token was rejected; the user has to authorize the
+    /// application again.
+    InvalidGrant,
+    /// The client ID or secret was rejected.
+    InvalidClient,
+    /// The user denied access.
+    UserDenied,
+    /// The user or a helper process did not respond in time.
+    Timeout,
+    /// The token storage could not be accessed.
+    Storage,
+    /// The request could not be made as configured, e.g. because of invalid scopes or an
+    /// unreadable credentials or token file.
+    Configuration,
+}
+
 /// Encapsulates all possible results of the `token(...)` operation
 #[derive(Debug)]
 pub enum RequestError {
@@ -55,11 +191,13 @@ pub enum RequestError {
     /// Some requested scopes were invalid. String contains the scopes as part of
     /// the server error message
     InvalidScope(String),
-    /// A 'catch-all' variant containing the server error and description
-    /// First string is the error code, the second may be a more detailed description
-    NegativeServerResponse(String, Option<String>),
+    /// A 'catch-all' variant containing the error response of the server
+    NegativeServerResponse(OAuthError),
     /// A malformed server response.
     BadServerResponse(String),
+    /// The server answered with an HTTP error status, but not with an OAuth error response;
+    /// e.g. a proxy returned an error page. Contains the status and the response body.
+    HttpStatus(hyper::StatusCode, String),
     /// Error while decoding a JSON response.
     JSONError(serde_json::error::Error),
     /// Error within user input.
@@ -68,10 +206,65 @@ pub enum RequestError {
     LowLevelError(io::Error),
     /// A poll error occurred in the DeviceFlow.
     Poll(PollError),
-    /// An error occurred while refreshing tokens.
-    Refresh(RefreshResult),
-    /// Error in token cache layer
-    Cache(Box<dyn Error + Send + Sync>),
+    /// The server refused to refresh a token.
+    Refresh(OAuthError),
+    /// Error in token cache layer, classified by the token storage.
+    Cache(ErrorKind, Box<dyn Error + Send + Sync>),
+}
+
+impl RequestError {
+    /// The kind of this error.
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            RequestError::ClientError(_) => ErrorKind::Transport,
+            RequestError::InvalidClient => ErrorKind::InvalidClient,
+            RequestError::InvalidScope(_) | RequestError::UserError(_) => ErrorKind::Configuration,
+            RequestError::NegativeServerResponse(ref e) | RequestError::Refresh(ref e) => {
+                e.code.kind()
+            }
+            RequestError::BadServerResponse(_) | RequestError::JSONError(_) => ErrorKind::Protocol,
+            RequestError::HttpStatus(status, _) => {
+                if status.is_server_error() || status == hyper::StatusCode::TOO_MANY_REQUESTS {
+                    ErrorKind::Transport
+                } else {
+                    ErrorKind::Protocol
+                }
+            }
+            RequestError::LowLevelError(ref e) => match e.kind() {
+                io::ErrorKind::TimedOut => ErrorKind::Timeout,
+                // E.g. missing or unreadable credential files.
+                io::ErrorKind::NotFound
+                | io::ErrorKind::PermissionDenied
+                | io::ErrorKind::InvalidInput
+                | io::ErrorKind::InvalidData => ErrorKind::Configuration,
+                _ => ErrorKind::Transport,
+            },
+            RequestError::Poll(ref e) => match *e {
+                PollError::HttpError(_) => ErrorKind::Transport,
+                PollError::Expired(_) | PollError::TimedOut => ErrorKind::Timeout,
+                PollError::AccessDenied => ErrorKind::UserDenied,
+                PollError::Other(_) => ErrorKind::Protocol,
+            },
+            RequestError::Cache(kind, _) => kind,
+        }
+    }
+
+    /// Returns true if repeating the request may succeed, i.e. if the error is likely
+    /// temporary.
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            RequestError::NegativeServerResponse(ref e) | RequestError::Refresh(ref e) => {
+                e.code == OAuthErrorCode::ServerError
+                    || e.code == OAuthErrorCode::TemporarilyUnavailable
+            }
+            _ => {
+                let kind = self.kind();
+                kind == ErrorKind::Transport
+                    || kind == ErrorKind::Timeout
+                    || kind == ErrorKind::Storage
+            }
+        }
+    }
 }
 
 impl From<hyper::Error> for RequestError {
@@ -89,7 +282,7 @@ impl From<JsonError> for RequestError {
                     .error_description
                     .unwrap_or("no description provided".to_string()),
             ),
-            _ => RequestError::NegativeServerResponse(value.error, value.error_description),
+            _ => RequestError::NegativeServerResponse(value.into()),
         }
     }
 }
@@ -99,15 +292,12 @@ impl fmt::Display for RequestError {
         match *self {
             RequestError::ClientError(ref err) => err.fmt(f),
             RequestError::InvalidClient => "Invalid Client".fmt(f),
-            RequestError::InvalidScope(ref scope) => writeln!(f, "Invalid Scope: '{}'", scope),
-            RequestError::NegativeServerResponse(ref error, ref desc) => {
-                error.fmt(f)?;
-                if let &Some(ref desc) = desc {
-                    write!(f, ": {}", desc)?;
-                }
-                "\n".fmt(f)
-            }
+            RequestError::InvalidScope(ref scope) => write!(f, "Invalid Scope: '{}'", scope),
+            RequestError::NegativeServerResponse(ref e) => e.fmt(f),
             RequestError::BadServerResponse(ref s) => s.fmt(f),
+            RequestError::HttpStatus(status, ref body) => {
+                write!(f, "Server responded with {}: {}", status, body)
+            }
             RequestError::JSONError(ref e) => format!(
                 "JSON Error; this might be a bug with unexpected server responses! {}",
                 e
@@ -116,8 +306,8 @@ impl fmt::Display for RequestError {
             RequestError::UserError(ref s) => s.fmt(f),
             RequestError::LowLevelError(ref e) => e.fmt(f),
             RequestError::Poll(ref pe) => pe.fmt(f),
-            RequestError::Refresh(ref rr) => format!("{:?}", rr).fmt(f),
-            RequestError::Cache(ref e) => e.fmt(f),
+            RequestError::Refresh(ref e) => write!(f, "Token refresh failed: {}", e),
+            RequestError::Cache(_, ref e) => e.fmt(f),
         }
     }
 }
@@ -128,6 +318,10 @@ impl Error for RequestError {
             RequestError::ClientError(ref err) => Some(err),
             RequestError::LowLevelError(ref err) => Some(err),
             RequestError::JSONError(ref err) => Some(err),
+            RequestError::NegativeServerResponse(ref err) => Some(err),
+            RequestError::Refresh(ref err) => Some(err),
+            RequestError::Poll(ref err) => Some(err),
+            RequestError::Cache(_, ref err) => Some(&**err),
             _ => None,
         }
     }
@@ -281,6 +475,26 @@ pub struct Token {
     /// timestamp is seconds since epoch indicating when the token will expire in absolute terms.
     /// use expiry_date() to convert to DateTime.
     pub expires_in_timestamp: Option<i64>,
+    /// Some providers issue refresh tokens that expire; they will expire after this amount
+    /// of time. Prefer using refresh_token_expiry_date()
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token_expires_in: Option<i64>,
+    /// timestamp is seconds since epoch indicating when the refresh token will expire, if it
+    /// does.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token_expires_in_timestamp: Option<i64>,
+    /// The OpenID Connect ID token, if the `openid` scope was requested.
+    /// Use id_token_claims() to decode it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+    /// The scopes granted by the server, separated by spaces, if it reported them. They may
+    /// differ from the requested scopes. Use granted_scopes() to split them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    /// Further parameters of the token response, e.g. provider-specific ones like Slack's
+    /// `team`. They are kept when the token is stored.
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
 }
 
 impl Token {
@@ -306,8 +520,52 @@ impl Token {
         Utc.timestamp(expires_in_timestamp, 0).into()
     }
 
+    /// Returns true if our refresh token is known to have expired. A new token must be
+    /// obtained interactively then.
+    pub fn refresh_token_expired(&self) -> bool {
+        match self.refresh_token_expiry_date() {
+            Some(expiry_date) => expiry_date <= Utc::now(),
+            None => false,
+        }
+    }
+
+    /// Returns a DateTime object representing the expiry date of our refresh token, if it
+    /// expires.
+    pub fn refresh_token_expiry_date(&self) -> Option<DateTime<Utc>> {
+        Utc.timestamp_opt(self.refresh_token_expires_in_timestamp?, 0)
+            .single()
+    }
+
+    /// Returns the granted scopes, if known.
+    pub fn granted_scopes(&self) -> Option<Vec<&str>> {
+        self.scope.as_ref().map(|s| s.split_whitespace().collect())
+    }
+
+    /// Returns those of `scopes` that we don't grant. Nothing is returned if the granted scopes
+    /// are unknown.
+    pub fn missing_scopes<S: AsRef<str>>(&self, scopes: &[S]) -> Vec<String> {
+        match self.granted_scopes() {
+            Some(granted) => scopes
+                .iter()
+                .map(AsRef::as_ref)
+                .filter(|s| !granted.contains(s))
+                .map(str::to_string)
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Decodes the claims of our ID token, if we have one.
+    pub fn id_token_claims(&self) -> Option<Result<IdTokenClaims, io::Error>> {
+        self.id_token.as_ref().map(|t| IdTokenClaims::decode(t))
+    }
+
     /// Adjust our stored expiry format to be absolute, using the current time.
     pub fn set_expiry_absolute(&mut self) -> &mut Token {
+        if let Some(expires_in) = self.refresh_token_expires_in.take() {
+            self.refresh_token_expires_in_timestamp =
+                Some(Utc::now().timestamp().saturating_add(expires_in));
+        }
         if self.expires_in_timestamp.is_some() {
             assert!(self.expires_in.is_none());
             return self;
@@ -322,6 +580,29 @@ impl Token {
     }
 }
 
+/// The names of `Token`'s fields.
+const TOKEN_FIELDS: [&str; 9] = [
+    "access_token",
+    "refresh_token",
+    "token_type",
+    "expires_in",
+    "expires_in_timestamp",
+    "refresh_token_expires_in",
+    "refresh_token_expires_in_timestamp",
+    "id_token",
+    "scope",
+];
+
+/// Returns the further parameters of a token response for `Token::extra`, without those that
+/// are fields of `Token`. They would be serialized twice otherwise, and the stored token could
+/// not be read again.
+pub(crate) fn extra_parameters(
+    mut extra: HashMap<String, serde_json::Value>,
+) -> HashMap<String, serde_json::Value> {
+    extra.retain(|name, _| !TOKEN_FIELDS.contains(&name.as_str()));
+    extra
+}
+
 /// All known authentication types, for suitable constants
 #[derive(Clone)]
 pub enum FlowType {
@@ -415,4 +696,93 @@ pub mod tests {
         assert_eq!(auth.token_type, TokenType::Bearer);
         assert_eq!(auth.access_token, "foo".to_string());
     }
+
+    #[test]
+    fn set_expiry_absolute_saturates() {
+        let mut token: Token = serde_json::from_str(
+            r#"{"access_token": "a", "refresh_token": "r", "token_type": "Bearer", "expires_in": 3600, "refresh_token_expires_in": 9223372036854775807}"#,
+        )
+        .unwrap();
+        token.set_expiry_absolute();
+        assert_eq!(Some(i64::MAX), token.refresh_token_expires_in_timestamp);
+        assert_eq!(None, token.refresh_token_expiry_date());
+        assert!(!token.refresh_token_expired());
+    }
+
+    #[test]
+    fn error_kinds() {
+        let response = |body: &str| -> RequestError {
+            serde_json::from_str::<JsonError>(body).unwrap().into()
+        };
+
+        let err = response(r#"{"error": "invalid_grant", "error_description": "Bad Request"}"#);
+        assert_eq!(ErrorKind::InvalidGrant, err.kind());
+        assert!(!err.is_retryable());
+        assert_eq!("invalid_grant: Bad Request", err.to_string());
+
+        let err = response(r#"{"error": "temporarily_unavailable"}"#);
+        assert_eq!(ErrorKind::Protocol, err.kind());
+        assert!(err.is_retryable());
+
+        let err = response(r#"{"error": "access_denied"}"#);
+        assert_eq!(ErrorKind::UserDenied, err.kind());
+        assert_eq!(
+            ErrorKind::InvalidClient,
+            response(r#"{"error": "invalid_client"}"#).kind()
+        );
+        assert_eq!(
+            ErrorKind::Configuration,
+            response(r#"{"error": "invalid_scope"}"#).kind()
+        );
+
+        match response(r#"{"error": "authorization_pending", "error_uri": "https://example.com"}"#)
+        {
+            RequestError::NegativeServerResponse(e) => {
+                assert_eq!(
+                    OAuthErrorCode::Other("authorization_pending".to_string()),
+                    e.code
+                );
+                assert_eq!(Some("https://example.com"), e.uri.as_deref());
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let err = RequestError::Refresh(OAuthError::new("invalid_grant", None));
+        assert_eq!(ErrorKind::InvalidGrant, err.kind());
+        assert_eq!("Token refresh failed: invalid_grant", err.to_string());
+        assert_eq!("invalid_grant", err.source().unwrap().to_string());
+
+        let err = RequestError::HttpStatus(hyper::StatusCode::SERVICE_UNAVAILABLE, String::new());
+        assert_eq!(ErrorKind::Transport, err.kind());
+        assert!(err.is_retryable());
+        let err = RequestError::HttpStatus(hyper::StatusCode::NOT_FOUND, String::new());
+        assert_eq!(ErrorKind::Protocol, err.kind());
+        assert!(!err.is_retryable());
+
+        let err = RequestError::LowLevelError(io::Error::new(io::ErrorKind::TimedOut, "slow"));
+        assert_eq!(ErrorKind::Timeout, err.kind());
+        assert!(err.is_retryable());
+        for kind in &[
+            io::ErrorKind::Interrupted,
+            io::ErrorKind::UnexpectedEof,
+            io::ErrorKind::BrokenPipe,
+            io::ErrorKind::WouldBlock,
+        ] {
+            let err = RequestError::LowLevelError(io::Error::new(*kind, "cut off"));
+            assert_eq!(ErrorKind::Transport, err.kind());
+            assert!(err.is_retryable());
+        }
+        let err = RequestError::LowLevelError(io::Error::new(io::ErrorKind::NotFound, "key.json"));
+        assert_eq!(ErrorKind::Configuration, err.kind());
+        assert!(!err.is_retryable());
+        let err = RequestError::Poll(PollError::AccessDenied);
+        assert_eq!(ErrorKind::UserDenied, err.kind());
+        let err = RequestError::Cache(
+            ErrorKind::Storage,
+            Box::new(StringError::new("unavailable", None)),
+        );
+        assert_eq!(ErrorKind::Storage, err.kind());
+        assert!(err.is_retryable());
+        assert_eq!("unavailable", err.source().unwrap().to_string());
+    }
 }
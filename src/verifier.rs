@@ -0,0 +1,616 @@
+//! This module verifies ID tokens (and other JWTs with the same standard claims) against the
+//! public keys published by their issuer as a JSON Web Key Set
+//! ([RFC 7517](https://tools.ietf.org/html/rfc7517)).
+//!
+//! The key set is cached for as long as its `Cache-Control` header allows, and fetched again
+//! when a token is signed with a key that is not in the cached set, which happens after the
+//! issuer rotated its keys.
+
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::authenticator::{DefaultHyperClient, HyperClientBuilder};
+use crate::id_token::IdTokenClaims;
+use crate::types::RequestError;
+
+use chrono::{DateTime, Utc};
+use futures::stream::Stream;
+use futures::{future, prelude::*};
+use hyper::header;
+use ring::signature;
+
+/// Google's key set for ID tokens.
+pub const GOOGLE_JWKS_URL: &str = "https://www.googleapis.com/oauth2/v3/certs";
+/// The issuers of Google ID tokens.
+const GOOGLE_ISSUERS: [&str; 2] = ["https://accounts.google.com", "accounts.google.com"];
+
+/// How long a key set is cached if its response doesn't say.
+const DEFAULT_KEY_SET_LIFETIME: i64 = 300;
+/// Longer durations (in seconds) are clamped to this one, which keeps date arithmetic from
+/// overflowing.
+const MAX_DURATION_SECS: i64 = 100 * 365 * 24 * 60 * 60;
+
+/// Errors of `IdTokenVerifier::verify()`.
+#[derive(Debug)]
+pub enum IdTokenError {
+    /// The key set could not be fetched.
+    KeySet(RequestError),
+    /// The token is not a well-formed JWT, or lacks required claims.
+    Malformed(String),
+    /// The token is signed with an algorithm other than RS256 and ES256.
+    UnsupportedAlgorithm(String),
+    /// The token's key ID was not found in the key set, even after fetching it again.
+    UnknownKey(Option<String>),
+    /// The signature is invalid.
+    InvalidSignature,
+    /// The token has expired, or was issued in the future.
+    Expired,
+    /// The token was issued by an issuer that is not accepted.
+    InvalidIssuer(String),
+    /// The token is not intended for any accepted audience.
+    InvalidAudience(Vec<String>),
+}
+
+impl fmt::Display for IdTokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            IdTokenError::KeySet(ref e) => write!(f, "Could not fetch key set: {}", e),
+            IdTokenError::Malformed(ref s) => write!(f, "Malformed token: {}", s),
+            IdTokenError::UnsupportedAlgorithm(ref alg) => {
+                write!(f, "Unsupported signature algorithm {}", alg)
+            }
+            IdTokenError::UnknownKey(ref kid) => write!(f, "Unknown signing key {:?}", kid),
+            IdTokenError::InvalidSignature => "Invalid signature".fmt(f),
+            IdTokenError::Expired => "Token expired or not yet valid".fmt(f),
+            IdTokenError::InvalidIssuer(ref iss) => write!(f, "Issuer {} not accepted", iss),
+            IdTokenError::InvalidAudience(ref aud) => {
+                write!(f, "Audience {:?} not accepted", aud)
+            }
+        }
+    }
+}
+
+impl Error for IdTokenError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            IdTokenError::KeySet(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// A JSON Web Key, as far as it is needed to verify signatures.
+#[derive(Deserialize, Debug)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    #[serde(rename = "use")]
+    usage: Option<String>,
+    // RSA keys.
+    n: Option<String>,
+    e: Option<String>,
+    // EC keys.
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+impl Jwk {
+    /// Checks `signature` over `message` if this key is usable for `alg`. Returns None if it
+    /// isn't.
+    fn verify(&self, alg: &str, message: &[u8], sig: &[u8]) -> Option<bool> {
+        if self.usage.as_deref().unwrap_or("sig") != "sig" {
+            return None;
+        }
+        let decode = |s: &Option<String>| {
+            s.as_ref()
+                .and_then(|s| base64::decode_config(s, base64::URL_SAFE_NO_PAD).ok())
+        };
+        match (alg, self.kty.as_str()) {
+            ("RS256", "RSA") => {
+                let key = signature::RsaPublicKeyComponents {
+                    n: decode(&self.n)?,
+                    e: decode(&self.e)?,
+                };
+                Some(
+                    key.verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, sig)
+                        .is_ok(),
+                )
+            }
+            ("ES256", "EC") if self.crv.as_deref() == Some("P-256") => {
+                let mut point = vec![4];
+                point.extend(decode(&self.x)?);
+                point.extend(decode(&self.y)?);
+                let key =
+                    signature::UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point);
+                Some(key.verify(message, sig).is_ok())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A fetched key set.
+struct KeySet {
+    keys: Vec<Jwk>,
+    fetched_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+}
+
+impl KeySet {
+    fn has_key(&self, kid: &Option<String>) -> bool {
+        kid.is_none() || self.keys.iter().any(|k| k.kid == *kid)
+    }
+}
+
+/// The parts of a JWT that are needed for verification.
+struct ParsedToken {
+    alg: String,
+    kid: Option<String>,
+    message: Vec<u8>,
+    signature: Vec<u8>,
+    claims: IdTokenClaims,
+}
+
+impl ParsedToken {
+    fn parse(token: &str) -> Result<ParsedToken, IdTokenError> {
+        #[derive(Deserialize)]
+        struct Header {
+            alg: String,
+            kid: Option<String>,
+        }
+        let malformed = |e: &dyn fmt::Display| IdTokenError::Malformed(e.to_string());
+        let parts: Vec<&str> = token.split('.').collect();
+        if parts.len() != 3 {
+            return Err(malformed(&"not a JWT"));
+        }
+        let header =
+            base64::decode_config(parts[0], base64::URL_SAFE_NO_PAD).map_err(|e| malformed(&e))?;
+        let header: Header = serde_json::from_slice(&header).map_err(|e| malformed(&e))?;
+        if header.alg != "RS256" && header.alg != "ES256" {
+            return Err(IdTokenError::UnsupportedAlgorithm(header.alg));
+        }
+        Ok(ParsedToken {
+            alg: header.alg,
+            kid: header.kid,
+            message: token.as_bytes()[..parts[0].len() + 1 + parts[1].len()].to_vec(),
+            signature: base64::decode_config(parts[2], base64::URL_SAFE_NO_PAD)
+                .map_err(|e| malformed(&e))?,
+            claims: IdTokenClaims::decode(token).map_err(|e| malformed(&e))?,
+        })
+    }
+}
+
+/// Builds an `IdTokenVerifierImpl`.
+pub struct IdTokenVerifier<C> {
+    client: C,
+    jwks_url: String,
+    issuers: Vec<String>,
+    audiences: Vec<String>,
+    leeway: Duration,
+    min_refresh_interval: Duration,
+}
+
+impl IdTokenVerifier<DefaultHyperClient> {
+    /// Verify tokens issued by `issuer` for `audience`, with the keys from `jwks_url`.
+    pub fn new<U: Into<String>, I: Into<String>, A: Into<String>>(
+        jwks_url: U,
+        issuer: I,
+        audience: A,
+    ) -> Self {
+        IdTokenVerifier {
+            client: DefaultHyperClient,
+            jwks_url: jwks_url.into(),
+            issuers: vec![issuer.into()],
+            audiences: vec![audience.into()],
+            leeway: Duration::from_secs(60),
+            min_refresh_interval: Duration::from_secs(60),
+        }
+    }
+
+    /// Verify Google ID tokens for `audience`, usually your OAuth client ID.
+    pub fn google<A: Into<String>>(audience: A) -> Self {
+        IdTokenVerifier::new(GOOGLE_JWKS_URL, GOOGLE_ISSUERS[0], audience).issuer(GOOGLE_ISSUERS[1])
+    }
+}
+
+impl<C> IdTokenVerifier<C>
+where
+    C: HyperClientBuilder,
+    C::Connector: 'static,
+{
+    /// Use the provided hyper client.
+    pub fn hyper_client<NewC: HyperClientBuilder>(
+        self,
+        hyper_client: NewC,
+    ) -> IdTokenVerifier<NewC> {
+        IdTokenVerifier {
+            client: hyper_client,
+            jwks_url: self.jwks_url,
+            issuers: self.issuers,
+            audiences: self.audiences,
+            leeway: self.leeway,
+            min_refresh_interval: self.min_refresh_interval,
+        }
+    }
+
+    /// Accept tokens from another issuer as well.
+    pub fn issuer<I: Into<String>>(mut self, issuer: I) -> Self {
+        self.issuers.push(issuer.into());
+        self
+    }
+
+    /// Accept tokens for another audience as well.
+    pub fn audience<A: Into<String>>(mut self, audience: A) -> Self {
+        self.audiences.push(audience.into());
+        self
+    }
+
+    /// Tolerate this much clock skew when checking `exp` and `iat`. The default is one minute.
+    pub fn leeway(self, leeway: Duration) -> Self {
+        IdTokenVerifier { leeway, ..self }
+    }
+
+    /// Fetch the key set for an unknown key at most this often. The default is one minute; it
+    /// stops tokens with made-up key IDs from causing a request each.
+    pub fn min_refresh_interval(self, min_refresh_interval: Duration) -> Self {
+        IdTokenVerifier {
+            min_refresh_interval,
+            ..self
+        }
+    }
+
+    /// Build the configured verifier.
+    pub fn build(self) -> IdTokenVerifierImpl<C::Connector> {
+        IdTokenVerifierImpl {
+            client: self.client.build_hyper_client(),
+            jwks_url: self.jwks_url,
+            issuers: self.issuers,
+            audiences: self.audiences,
+            leeway: clamp_duration(self.leeway),
+            min_refresh_interval: clamp_duration(self.min_refresh_interval),
+            key_set: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+/// Verifies ID tokens; built by `IdTokenVerifier`.
+pub struct IdTokenVerifierImpl<C> {
+    client: hyper::Client<C, hyper::Body>,
+    jwks_url: String,
+    issuers: Vec<String>,
+    audiences: Vec<String>,
+    leeway: chrono::Duration,
+    min_refresh_interval: chrono::Duration,
+    key_set: Arc<Mutex<Option<KeySet>>>,
+}
+
+impl<C: 'static + hyper::client::connect::Connect> IdTokenVerifierImpl<C> {
+    /// Verify the signature and claims of `token`, returning its claims if it is valid.
+    pub fn verify(
+        &self,
+        token: &str,
+    ) -> Box<dyn Future<Item = IdTokenClaims, Error = IdTokenError> + Send> {
+        let token = match ParsedToken::parse(token) {
+            Ok(token) => token,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        if let Err(e) = self.check_claims(&token.claims) {
+            return Box::new(future::err(e));
+        }
+
+        let now = Utc::now();
+        let fetch = match *self.key_set.lock().unwrap() {
+            Some(ref key_set) if now < key_set.expires_at && key_set.has_key(&token.kid) => false,
+            // The key may be new, but don't fetch again too often.
+            Some(ref key_set) if now < key_set.expires_at => {
+                if now < key_set.fetched_at + self.min_refresh_interval {
+                    return Box::new(future::err(IdTokenError::UnknownKey(token.kid)));
+                }
+                true
+            }
+            _ => true,
+        };
+        let key_set = self.key_set.clone();
+        let verify = move |_| {
+            let key_set = key_set.lock().unwrap();
+            let keys = &key_set.as_ref().unwrap().keys;
+            let mut results = keys
+                .iter()
+                .filter(|k| token.kid.is_none() || k.kid == token.kid)
+                .filter_map(|k| k.verify(&token.alg, &token.message, &token.signature));
+            match results.next() {
+                None => Err(IdTokenError::UnknownKey(token.kid)),
+                // Without a key ID, any key will do.
+                Some(valid) if valid || results.any(|v| v) => Ok(token.claims),
+                Some(_) => Err(IdTokenError::InvalidSignature),
+            }
+        };
+        if fetch {
+            Box::new(self.fetch_key_set().and_then(verify))
+        } else {
+            Box::new(future::result(verify(())))
+        }
+    }
+
+    fn check_claims(&self, claims: &IdTokenClaims) -> Result<(), IdTokenError> {
+        let now = Utc::now().timestamp();
+        let leeway = self.leeway.num_seconds();
+        if claims.exp.saturating_add(leeway) < now || claims.iat.saturating_sub(leeway) > now {
+            return Err(IdTokenError::Expired);
+        }
+        if !self.issuers.contains(&claims.iss) {
+            return Err(IdTokenError::InvalidIssuer(claims.iss.clone()));
+        }
+        if !claims.aud.iter().any(|aud| self.audiences.contains(aud)) {
+            return Err(IdTokenError::InvalidAudience(claims.aud.clone()));
+        }
+        Ok(())
+    }
+
+    /// Fetch the key set and store it.
+    fn fetch_key_set(&self) -> impl Future<Item = (), Error = IdTokenError> {
+        let request = hyper::Request::get(&self.jwks_url)
+            .body(hyper::Body::empty())
+            .map_err(|e| IdTokenError::KeySet(RequestError::UserError(e.to_string())));
+        let client = self.client.clone();
+        let key_set = self.key_set.clone();
+        future::result(request)
+            .and_then(move |request| {
+                client
+                    .request(request)
+                    .and_then(|response| {
+                        let lifetime = response
+                            .headers()
+                            .get(header::CACHE_CONTROL)
+                            .and_then(|v| v.to_str().ok())
+                            .map(cache_lifetime)
+                            .unwrap_or(DEFAULT_KEY_SET_LIFETIME);
+                        let status = response.status();
+                        response
+                            .into_body()
+                            .concat2()
+                            .map(move |body| (status, lifetime, body))
+                    })
+                    .map_err(|e| IdTokenError::KeySet(RequestError::ClientError(e)))
+            })
+            .and_then(move |(status, lifetime, body)| {
+                if !status.is_success() {
+                    return Err(IdTokenError::KeySet(RequestError::BadServerResponse(
+                        format!("Key set request failed with {}", status),
+                    )));
+                }
+                let jwks: JwkSet = serde_json::from_slice(&body)
+                    .map_err(|e| IdTokenError::KeySet(RequestError::JSONError(e)))?;
+                let now = Utc::now();
+                *key_set.lock().unwrap() = Some(KeySet {
+                    keys: jwks.keys,
+                    fetched_at: now,
+                    expires_at: now + chrono::Duration::seconds(lifetime),
+                });
+                Ok(())
+            })
+    }
+}
+
+/// Converts `duration` for date arithmetic, clamping it at `MAX_DURATION_SECS`.
+fn clamp_duration(duration: Duration) -> chrono::Duration {
+    let max = chrono::Duration::seconds(MAX_DURATION_SECS);
+    chrono::Duration::from_std(duration).unwrap_or(max).min(max)
+}
+
+/// Returns the number of seconds a response may be cached according to its `Cache-Control`
+/// header.
+fn cache_lifetime(cache_control: &str) -> i64 {
+    let mut lifetime = DEFAULT_KEY_SET_LIFETIME;
+    for directive in cache_control.split(',').map(str::trim) {
+        let directive = directive.to_ascii_lowercase();
+        if directive == "no-cache" || directive == "no-store" {
+            return 0;
+        }
+        if let Some(max_age) = directive.strip_prefix("max-age=") {
+            lifetime = max_age.parse().unwrap_or(0).clamp(0, MAX_DURATION_SECS);
+        }
+    }
+    lifetime
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::service_account_key_from_file;
+    use crate::service_account::{PrivateKeySigner, Signer};
+
+    use mockito::{self, mock};
+    use ring::signature::KeyPair;
+
+    const TEST_PRIVATE_KEY_PATH: &str = "examples/Sanguine-69411a0c0eea.json";
+    /// The public key of TEST_PRIVATE_KEY_PATH.
+    const TEST_MODULUS: &str = "19Yf1IkmxwX93OS94QG-EFGef5y_Qk2NdZvLl5lgBmgIY5RO779eY02sYYypvkaXyVt7IWWqNJcUiYGsmGPFG-Ssd56T306H3_zf7AVe0ZmeBWG-m9V7GaY08uAbdS6UbUqvy-JR_m6DxggXSb-7sCZHQU0TwSUZ8p5aTTq3idPH6PNBuqmkJ9mQNz_GLrKJyEu9oVvrXZ4a66cb2yCU_zTdnRX1V8bNypC-kLtxbmqrxAEanY7jznYw5A0cAOIH05WoBj7SVUOIFJeq4F0U19qNIhi1yMXoR3y622D-VDo636QIk0XX_hNx6OSiabNPTKx5Oclc2rihFGrifTPmtw";
+
+    fn sign(signer: &dyn Signer, claims: serde_json::Value) -> String {
+        let header = serde_json::json!({"alg": signer.algorithm(), "kid": signer.key_id()});
+        let message = format!(
+            "{}.{}",
+            base64::encode_config(&header.to_string(), base64::URL_SAFE_NO_PAD),
+            base64::encode_config(&claims.to_string(), base64::URL_SAFE_NO_PAD)
+        );
+        let signature = signer.sign(message.as_bytes()).unwrap();
+        format!(
+            "{}.{}",
+            message,
+            base64::encode_config(&signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    fn claims(aud: &str, exp_in: i64) -> serde_json::Value {
+        let now = Utc::now().timestamp();
+        serde_json::json!({
+            "iss": "https://issuer.example.com", "sub": "1234", "aud": aud,
+            "iat": now, "exp": now + exp_in,
+        })
+    }
+
+    #[test]
+    fn test_cache_lifetime() {
+        assert_eq!(
+            3600,
+            cache_lifetime("public, max-age=3600, must-revalidate")
+        );
+        assert_eq!(0, cache_lifetime("no-store"));
+        assert_eq!(DEFAULT_KEY_SET_LIFETIME, cache_lifetime("public"));
+        assert_eq!(0, cache_lifetime("max-age=-60"));
+        assert_eq!(
+            MAX_DURATION_SECS,
+            cache_lifetime("max-age=9000000000000000000")
+        );
+    }
+
+    #[test]
+    fn test_build_clamps_durations() {
+        let v = IdTokenVerifier::new(GOOGLE_JWKS_URL, "https://issuer.example.com", "client")
+            .leeway(Duration::from_secs(u64::MAX))
+            .min_refresh_interval(Duration::from_secs(u64::MAX))
+            .build();
+        assert_eq!(MAX_DURATION_SECS, v.leeway.num_seconds());
+        assert_eq!(MAX_DURATION_SECS, v.min_refresh_interval.num_seconds());
+    }
+
+    #[test]
+    fn test_invalid_key_set_url() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let v = IdTokenVerifier::new(
+            "http://bad host/certs",
+            "https://issuer.example.com",
+            "client",
+        )
+        .build();
+        let key = service_account_key_from_file(TEST_PRIVATE_KEY_PATH).unwrap();
+        let rsa =
+            PrivateKeySigner::new(key.private_key.as_ref().unwrap(), Some("rsa1".to_string()))
+                .unwrap();
+        let issued = claims("client", 600);
+        match rt.block_on(v.verify(&sign(&rsa, issued))) {
+            Err(IdTokenError::KeySet(RequestError::UserError(_))) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let key = service_account_key_from_file(TEST_PRIVATE_KEY_PATH).unwrap();
+        let rsa =
+            PrivateKeySigner::new(key.private_key.as_ref().unwrap(), Some("rsa1".to_string()))
+                .unwrap();
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = signature::EcdsaKeyPair::generate_pkcs8(
+            &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+            &rng,
+        )
+        .unwrap();
+        let point = signature::EcdsaKeyPair::from_pkcs8(
+            &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+            pkcs8.as_ref(),
+        )
+        .unwrap()
+        .public_key()
+        .as_ref()
+        .to_vec();
+        let ec = PrivateKeySigner::from_der(pkcs8.as_ref(), Some("ec1".to_string())).unwrap();
+
+        let rsa_jwk = serde_json::json!({
+            "kty": "RSA", "kid": "rsa1", "use": "sig", "alg": "RS256", "n": TEST_MODULUS, "e": "AQAB",
+        });
+        let ec_jwk = serde_json::json!({
+            "kty": "EC", "kid": "ec1", "crv": "P-256",
+            "x": base64::encode_config(&point[1..33], base64::URL_SAFE_NO_PAD),
+            "y": base64::encode_config(&point[33..], base64::URL_SAFE_NO_PAD),
+        });
+        let verifier = || {
+            IdTokenVerifier::new(
+                format!("{}/certs", mockito::server_url()),
+                "https://issuer.example.com",
+                "client",
+            )
+        };
+
+        // The key set is fetched once, and an unknown key doesn't cause another fetch right
+        // away.
+        let _m = mock("GET", "/certs")
+            .with_header("cache-control", "public, max-age=3600")
+            .with_body(serde_json::json!({ "keys": [rsa_jwk] }).to_string())
+            .expect(1)
+            .create();
+        let v = verifier().build();
+        let token = sign(&rsa, claims("client", 600));
+        for _ in 0..2 {
+            let claims = rt.block_on(v.verify(&token)).unwrap();
+            assert_eq!("1234", claims.sub);
+        }
+        match rt.block_on(v.verify(&sign(&ec, claims("client", 600)))) {
+            Err(IdTokenError::UnknownKey(Some(ref kid))) if kid == "ec1" => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        // A valid signature of another token.
+        let other = sign(&rsa, claims("client", 900));
+        let tampered = format!(
+            "{}.{}",
+            &other[..other.rfind('.').unwrap()],
+            &token[token.rfind('.').unwrap() + 1..]
+        );
+        match rt.block_on(v.verify(&tampered)) {
+            Err(IdTokenError::InvalidSignature) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        match rt.block_on(v.verify(&sign(&rsa, claims("other", 600)))) {
+            Err(IdTokenError::InvalidAudience(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        match rt.block_on(v.verify(&sign(&rsa, claims("client", -600)))) {
+            Err(IdTokenError::Expired) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        // Within the leeway.
+        assert!(rt
+            .block_on(v.verify(&sign(&rsa, claims("client", -30))))
+            .is_ok());
+        let mut foreign = claims("client", 600);
+        foreign["iss"] = "https://evil.example.com".into();
+        match rt.block_on(v.verify(&sign(&rsa, foreign))) {
+            Err(IdTokenError::InvalidIssuer(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        _m.assert();
+        drop(_m);
+
+        // A rotated key set is fetched when a new key shows up.
+        let _m = mock("GET", "/certs")
+            .with_header("cache-control", "public, max-age=3600")
+            .with_body(serde_json::json!({ "keys": [rsa_jwk] }).to_string())
+            .expect(1)
+            .create();
+        let v = verifier()
+            .min_refresh_interval(Duration::from_secs(0))
+            .build();
+        assert!(rt.block_on(v.verify(&token)).is_ok());
+        _m.assert();
+        drop(_m);
+        let _m = mock("GET", "/certs")
+            .with_body(serde_json::json!({ "keys": [rsa_jwk, ec_jwk] }).to_string())
+            .expect(1)
+            .create();
+        let claims = rt
+            .block_on(v.verify(&sign(&ec, claims("client", 600))))
+            .unwrap();
+        assert_eq!(vec!["client".to_string()], claims.aud);
+        _m.assert();
+    }
+}